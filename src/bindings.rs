@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     fs::{self, create_dir},
     io::{self, Write},
 };
@@ -8,15 +9,14 @@ use crate::Result;
 use camino::Utf8Path;
 use uniffi_bindgen::bindings::{GenerateOptions, TargetLanguage};
 
-use crate::recreate_dir;
+use crate::{recreate_dir, sorted_dir_entries};
 
 /// Generates UniFFI bindings for crate and returns the FFI module name.
 ///
 /// This function respects the `ffi_module_name` and `ffi_module_filename` settings
 /// in uniffi.toml. The returned FFI module name is detected from the generated
 /// header files, which reflect whatever is configured in uniffi.toml.
-pub fn generate_bindings(lib_path: &Utf8Path) -> Result<String> {
-    let out_dir = Utf8Path::new("./generated");
+pub fn generate_bindings(lib_path: &Utf8Path, out_dir: &Utf8Path) -> Result<String> {
     let headers = out_dir.join("headers");
     let sources = out_dir.join("sources");
 
@@ -38,28 +38,10 @@ pub fn generate_bindings(lib_path: &Utf8Path) -> Result<String> {
         .append(true)
         .open(headers.join("module.modulemap"))?;
 
-    // Detect the FFI module name from the generated header file.
-    // This respects ffi_module_name/ffi_module_filename from uniffi.toml.
-    let ffi_module_name = fs::read_dir(out_dir)?
-        .filter_map(|entry| entry.ok())
-        .find(|entry| {
-            entry.path().extension().is_some_and(|ext| ext == "h")
-                && entry
-                    .path()
-                    .file_stem()
-                    .is_some_and(|stem| !stem.to_string_lossy().contains("BridgingHeader"))
-        })
-        .ok_or_else(|| anyhow!("Could not find generated header file in {}", out_dir))?
-        .path()
-        .file_stem()
-        .unwrap()
-        .to_string_lossy()
-        .to_string();
+    let ffi_module_name = ffi_module_name(out_dir)?;
 
-    let dir = fs::read_dir(out_dir)?;
-
-    for f in dir {
-        let f = f?;
+    // Sorted, so that the parts of the modulemap are always concatenated in the same order
+    for f in sorted_dir_entries(out_dir)? {
         let file_path = f.path();
 
         if !f.metadata()?.is_file() {
@@ -87,3 +69,42 @@ pub fn generate_bindings(lib_path: &Utf8Path) -> Result<String> {
 
     Ok(ffi_module_name)
 }
+
+/// Detects the FFI module name from the header file generated into `out_dir`.
+/// This respects ffi_module_name/ffi_module_filename from uniffi.toml.
+pub fn ffi_module_name(out_dir: &Utf8Path) -> Result<String> {
+    let ffi_module_name = sorted_dir_entries(out_dir)?
+        .into_iter()
+        .find(|entry| {
+            entry.path().extension().is_some_and(|ext| ext == "h")
+                && entry
+                    .path()
+                    .file_stem()
+                    .is_some_and(|stem| !stem.to_string_lossy().contains("BridgingHeader"))
+        })
+        .ok_or_else(|| anyhow!("Could not find generated header file in {}", out_dir))?
+        .path()
+        .file_stem()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    Ok(ffi_module_name)
+}
+
+/// Returns whether the bindings generated into both directories are identical
+pub fn same_bindings(dir: &Utf8Path, other: &Utf8Path) -> Result<bool> {
+    Ok(read_generated_files(dir)? == read_generated_files(other)?)
+}
+
+fn read_generated_files(dir: &Utf8Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.insert(entry.file_name().to_owned(), fs::read(entry.path())?);
+        }
+    }
+
+    Ok(files)
+}
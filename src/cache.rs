@@ -0,0 +1,264 @@
+//! Fingerprints of the packaging stages of the last successful run.
+//!
+//! cargo keeps its own fingerprints of crate sources, features, profile and toolchain, so the
+//! stages after `cargo build` are keyed by the contents of the libraries it produced together
+//! with their remaining inputs. A stage is reused if its fingerprint matches the one recorded
+//! after it last succeeded and its output still exists.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::sha256::Sha256;
+use crate::{sorted_dir_entries, Result};
+
+/// Fingerprints of the last successful run of each stage for a single crate
+pub struct StageCache {
+    path: Utf8PathBuf,
+    fingerprints: BTreeMap<String, String>,
+}
+
+impl StageCache {
+    /// Loads the fingerprints of `crate_name` from the cargo target dir. A missing or unreadable
+    /// cache is treated as empty.
+    pub fn load(target_dir: &Utf8Path, crate_name: &str) -> Self {
+        let path = target_dir
+            .join("cargo-swift")
+            .join("fingerprints")
+            .join(format!("{crate_name}.json"));
+        let fingerprints = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+
+        Self { path, fingerprints }
+    }
+
+    /// Returns true if `stage` last succeeded with the same fingerprint and `output` exists
+    pub fn is_fresh(&self, stage: &str, fingerprint: &Fingerprint, output: &Path) -> bool {
+        output.exists() && self.fingerprints.get(stage) == Some(&fingerprint.hash)
+    }
+
+    /// Forgets the fingerprint of `stage` before it is run again, so that outputs of a failed
+    /// run are never reused
+    pub fn invalidate(&mut self, stage: &str) -> Result<()> {
+        if self.fingerprints.remove(stage).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Records the fingerprint of a successful run of `stage`
+    pub fn record(&mut self, stage: &str, fingerprint: &Fingerprint) -> Result<()> {
+        self.fingerprints
+            .insert(stage.to_owned(), fingerprint.hash.clone());
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create cache directory {parent}"))?;
+        }
+        let data = serde_json::to_vec_pretty(&self.fingerprints)
+            .context("Failed to serialize stage fingerprints")?;
+        fs::write(&self.path, data)
+            .with_context(|| format!("Failed to write stage fingerprints to {}", self.path))?;
+
+        Ok(())
+    }
+}
+
+/// Hash of all inputs of a stage
+pub struct Fingerprint {
+    hash: String,
+}
+
+/// Collects the inputs of a stage. Every input is labeled, so that moving a value from one
+/// input to another changes the fingerprint.
+pub struct FingerprintBuilder {
+    hasher: Sha256,
+}
+
+impl FingerprintBuilder {
+    pub fn new(stage: &str) -> Self {
+        let mut builder = Self {
+            hasher: Sha256::default(),
+        };
+        // Outputs of another version of cargo-swift may differ for the same inputs
+        builder.value("cargo-swift", env!("CARGO_PKG_VERSION"));
+        builder.value("stage", stage);
+        builder
+    }
+
+    pub fn value(&mut self, label: &str, value: impl AsRef<[u8]>) -> &mut Self {
+        let value = value.as_ref();
+        self.hasher.update(label.as_bytes());
+        self.hasher.update(&(value.len() as u64).to_le_bytes());
+        self.hasher.update(value);
+        self
+    }
+
+    /// Adds the contents of the file at `path`, or a marker if it does not exist
+    pub fn file(&mut self, label: &str, path: &Path) -> Result<&mut Self> {
+        if !path.exists() {
+            return Ok(self.value(label, "<missing>"));
+        }
+        let contents = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+        Ok(self.value(label, contents))
+    }
+
+    /// Adds the names and contents of all files in the directory at `path` and its
+    /// subdirectories, in sorted order
+    pub fn dir(&mut self, label: &str, path: &Path) -> Result<&mut Self> {
+        let entries = sorted_dir_entries(path)
+            .with_context(|| format!("Failed to read directory {path:?}"))?;
+
+        for entry in entries {
+            let name = entry.file_name();
+            let label = format!("{label}/{}", name.to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                self.dir(&label, &entry.path())?;
+            } else {
+                self.file(&label, &entry.path())?;
+            }
+        }
+
+        Ok(self)
+    }
+
+    pub fn finish(&mut self) -> Fingerprint {
+        Fingerprint {
+            hash: self.hasher.clone().finish_hex(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> Utf8PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("cargo-swift-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Utf8PathBuf::try_from(dir).unwrap()
+    }
+
+    fn fingerprint(value: &str) -> Fingerprint {
+        FingerprintBuilder::new("test")
+            .value("value", value)
+            .finish()
+    }
+
+    #[test]
+    fn reuses_recorded_stage_with_existing_output() {
+        let dir = test_dir("record");
+        let output = dir.join("Package.swift");
+        fs::write(&output, "").unwrap();
+
+        let mut cache = StageCache::load(&dir, "greeter");
+        assert!(!cache.is_fresh("package", &fingerprint("a"), output.as_std_path()));
+        cache.record("package", &fingerprint("a")).unwrap();
+        assert!(cache.is_fresh("package", &fingerprint("a"), output.as_std_path()));
+        assert!(!cache.is_fresh("package", &fingerprint("b"), output.as_std_path()));
+        assert!(!cache.is_fresh("xcframework", &fingerprint("a"), output.as_std_path()));
+
+        // Fingerprints are kept across runs, separately for every crate
+        let reloaded = StageCache::load(&dir, "greeter");
+        assert!(reloaded.is_fresh("package", &fingerprint("a"), output.as_std_path()));
+        let other_crate = StageCache::load(&dir, "other");
+        assert!(!other_crate.is_fresh("package", &fingerprint("a"), output.as_std_path()));
+
+        fs::remove_file(&output).unwrap();
+        assert!(!reloaded.is_fresh("package", &fingerprint("a"), output.as_std_path()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn forgets_invalidated_stage() {
+        let dir = test_dir("invalidate");
+        let output = dir.join("Package.swift");
+        fs::write(&output, "").unwrap();
+
+        let mut cache = StageCache::load(&dir, "greeter");
+        cache.record("package", &fingerprint("a")).unwrap();
+        cache.record("xcframework", &fingerprint("a")).unwrap();
+        cache.invalidate("package").unwrap();
+
+        let reloaded = StageCache::load(&dir, "greeter");
+        assert!(!reloaded.is_fresh("package", &fingerprint("a"), output.as_std_path()));
+        assert!(reloaded.is_fresh("xcframework", &fingerprint("a"), output.as_std_path()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fingerprints_labeled_inputs() {
+        let hash = |builder: &mut FingerprintBuilder| builder.finish().hash;
+
+        assert_eq!(
+            hash(FingerprintBuilder::new("a").value("x", "1")),
+            hash(FingerprintBuilder::new("a").value("x", "1"))
+        );
+        assert_ne!(
+            hash(FingerprintBuilder::new("a").value("x", "1")),
+            hash(FingerprintBuilder::new("b").value("x", "1"))
+        );
+        assert_ne!(
+            hash(FingerprintBuilder::new("a").value("x", "1")),
+            hash(FingerprintBuilder::new("a").value("y", "1"))
+        );
+        // Values are delimited by their length
+        assert_ne!(
+            hash(
+                FingerprintBuilder::new("a")
+                    .value("x", "12")
+                    .value("x", "3")
+            ),
+            hash(
+                FingerprintBuilder::new("a")
+                    .value("x", "1")
+                    .value("x", "23")
+            )
+        );
+    }
+
+    #[test]
+    fn fingerprints_files_and_directories() {
+        let dir = test_dir("files");
+        let file = dir.join("sources/Greeter.swift");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "v1").unwrap();
+        let fingerprint_dir = || {
+            FingerprintBuilder::new("package")
+                .dir("sources", dir.join("sources").as_std_path())
+                .unwrap()
+                .finish()
+                .hash
+        };
+        let fingerprint_file = || {
+            FingerprintBuilder::new("package")
+                .file("source", file.as_std_path())
+                .unwrap()
+                .finish()
+                .hash
+        };
+
+        let (dir_v1, file_v1) = (fingerprint_dir(), fingerprint_file());
+        fs::write(&file, "v2").unwrap();
+        assert_ne!(fingerprint_dir(), dir_v1);
+        assert_ne!(fingerprint_file(), file_v1);
+
+        fs::write(&file, "v1").unwrap();
+        assert_eq!(fingerprint_dir(), dir_v1);
+        fs::rename(&file, dir.join("sources/Renamed.swift")).unwrap();
+        assert_ne!(fingerprint_dir(), dir_v1);
+        // A missing file is an input as well
+        assert_ne!(fingerprint_file(), file_v1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
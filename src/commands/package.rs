@@ -1,28 +1,48 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
+use std::fs;
+use std::num::NonZeroUsize;
 use std::ops::Not;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
-use camino::Utf8PathBuf;
-use cargo_metadata::{Package, TargetKind};
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::{Metadata, Package};
 use clap::builder::TypedValueParser;
 use clap::{Args, ValueEnum};
 use convert_case::{Case, Casing};
 use dialoguer::{Input, MultiSelect};
 use execute::{command, Execute};
 use indicatif::MultiProgress;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 
-use crate::bindings::generate_bindings;
+use crate::bindings::{ffi_module_name, generate_bindings, same_bindings};
+use crate::cache::{Fingerprint, FingerprintBuilder, StageCache};
 use crate::console::*;
-use crate::console::{run_step, run_step_with_commands};
+use crate::console::{run_step, run_step_with_concurrent_commands};
 use crate::lib_type::LibType;
-use crate::metadata::{metadata, MetadataExt};
-use crate::swiftpackage::{create_swiftpackage, recreate_output_dir};
+use crate::metadata::{library_target, load_metadata, save_swiftpackage_options, MetadataExt};
+use crate::reproducible::{
+    checksums, differences, make_reproducible, normalize_mtimes, source_date_epoch,
+};
+use crate::rustflags::{inherit_rustflags, CargoConfig};
+use crate::sha256::file_checksum;
+use crate::swiftpackage::{
+    create_staging_dir, create_swiftpackage, remove_staging_dir, replace_output_dir, staging_dir,
+    LinkerSettings, ManifestSettings,
+};
 use crate::targets::*;
+use crate::templating::{ManifestCode, RemoteBinaryTarget};
+use crate::version::Version;
 use crate::xcframework::create_xcframework;
+use crate::zip::zip_dir;
+use crate::{copy_dir, remove_dir_if_exists};
 
-#[derive(ValueEnum, Debug, Clone)]
+#[derive(ValueEnum, Deserialize, Serialize, Debug, Clone)]
 #[value()]
+#[serde(rename_all = "lowercase")]
 pub enum LibTypeArg {
     Automatic,
     Dynamic,
@@ -49,32 +69,286 @@ impl From<LibTypeArg> for Option<LibType> {
     }
 }
 
-#[derive(Debug, Clone)]
+/// How the XCFramework is referenced by the Swift package
+#[derive(ValueEnum, Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[value()]
+#[serde(rename_all = "lowercase")]
+pub enum Distribution {
+    /// The XCFramework is part of the package
+    #[default]
+    Local,
+    /// The XCFramework is zipped for upload and downloaded by SwiftPM from a URL
+    Remote,
+}
+
+impl Display for Distribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Local => write!(f, "local"),
+            Self::Remote => write!(f, "remote"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
 pub struct FeatureOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub features: Option<Vec<String>>,
-    pub all_features: bool,
-    pub no_default_features: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub all_features: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_default_features: Option<bool>,
+}
+
+impl FeatureOptions {
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            features: self.features.or(fallback.features),
+            all_features: self.all_features.or(fallback.all_features),
+            no_default_features: self.no_default_features.or(fallback.no_default_features),
+        }
+    }
+
+    /// Enables the features of `other` in addition to these features. The flags of `other`
+    /// override these flags if they are set.
+    fn extend(&mut self, other: &Self) {
+        if let Some(features) = &other.features {
+            let enabled = self.features.get_or_insert_with(Vec::new);
+            enabled.extend(features.iter().cloned());
+            enabled.sort();
+            enabled.dedup();
+        }
+        self.all_features = other.all_features.or(self.all_features);
+        self.no_default_features = other.no_default_features.or(self.no_default_features);
+    }
+
+    pub(crate) fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(features) = &self.features {
+            args.push("--features".to_owned());
+            args.push(features.join(","));
+        }
+        if self.all_features.unwrap_or_default() {
+            args.push("--all-features".to_owned());
+        }
+        if self.no_default_features.unwrap_or_default() {
+            args.push("--no-default-features".to_owned());
+        }
+        args
+    }
+}
+
+impl Display for FeatureOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let args = self.args();
+        if args.is_empty() {
+            write!(f, "default features")
+        } else {
+            write!(f, "{}", args.join(" "))
+        }
+    }
+}
+
+/// Features, environment variables and rustflags for building a platform or a single Rust target
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TargetSettings {
+    /// Cargo features enabled in addition to the features of the package options
+    #[serde(flatten)]
+    pub features: FeatureOptions,
+    /// Flags passed to rustc when building for the target
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rustflags: Vec<String>,
+    /// Environment variables set for cargo when building for the target
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl TargetSettings {
+    /// Applies `other` on top of these settings. Features and rustflags are appended,
+    /// environment variables are overridden.
+    fn merge(&mut self, other: &Self) {
+        self.features.extend(&other.features);
+        self.rustflags.extend(other.rustflags.iter().cloned());
+        self.env
+            .extend(other.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+}
+
+const DEFAULT_SWIFT_TOOLS_VERSION: &str = "5.5";
+
+/// Options for packaging a crate. Each option can be given as command-line argument or in the
+/// `[package.metadata.swiftpackage]` or `[workspace.metadata.swiftpackage]` table of a Cargo.toml.
+/// Options that are not set by any of these are prompted for or fall back to their default.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PackageOptions {
+    #[serde(rename = "name", skip_serializing_if = "Option::is_none")]
+    pub package_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platforms: Option<Vec<PlatformSpec>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip)]
+    pub xcframework_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suppress_warnings: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release: Option<bool>,
+    /// Name of the cargo profile to build with, takes precedence over `release`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lib_type: Option<LibTypeArg>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_toolchains_check: Option<bool>,
+    #[serde(flatten)]
+    pub features: FeatureOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swift_tools_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privacy_manifest: Option<PathBuf>,
+    /// Directory to create the package directory in, defaults to the current directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_dir: Option<PathBuf>,
+    /// Directory of hand-written Swift sources to merge into the generated module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swift_sources: Option<PathBuf>,
+    /// Directory of resources to bundle with the generated module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swift_resources: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distribution: Option<Distribution>,
+    /// URL of the zipped XCFramework for remote distribution, may contain `{name}` and `{version}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle_identifier: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_arch: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<NonZeroUsize>,
+    /// Keep the package replaced by a run as `<package>.bak`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_backup: Option<bool>,
+    /// Build byte-identical packages from the same sources
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reproducible: Option<bool>,
+    /// Build settings keyed by platform (e.g. `ios`) or Rust target triple
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub target_settings: BTreeMap<String, TargetSettings>,
+    /// Dependencies, linker settings and Swift settings added to Package.swift
+    #[serde(skip_serializing_if = "ManifestSettings::is_empty")]
+    pub manifest: ManifestSettings,
+    /// Name of the profile in `[package.metadata.swiftpackage.profiles]` to read options from
+    #[serde(skip)]
+    pub profile_set: Option<String>,
+}
+
+impl PackageOptions {
+    /// Fills every option that is not set in `self` with the value from `fallback`
+    pub(crate) fn or(self, fallback: Self) -> Self {
+        // `release` and `profile` both select the build profile and are therefore overridden
+        // together
+        let (release, profile) = if self.release.is_some() || self.profile.is_some() {
+            (self.release, self.profile)
+        } else {
+            (fallback.release, fallback.profile)
+        };
+        let mut target_settings = fallback.target_settings;
+        target_settings.extend(self.target_settings);
+
+        Self {
+            platforms: self.platforms.or(fallback.platforms),
+            target: self.target.or(fallback.target),
+            package_name: self.package_name.or(fallback.package_name),
+            xcframework_name: self.xcframework_name.or(fallback.xcframework_name),
+            suppress_warnings: self.suppress_warnings.or(fallback.suppress_warnings),
+            release,
+            profile,
+            lib_type: self.lib_type.or(fallback.lib_type),
+            skip_toolchains_check: self
+                .skip_toolchains_check
+                .or(fallback.skip_toolchains_check),
+            features: self.features.or(fallback.features),
+            swift_tools_version: self.swift_tools_version.or(fallback.swift_tools_version),
+            privacy_manifest: self.privacy_manifest.or(fallback.privacy_manifest),
+            output_dir: self.output_dir.or(fallback.output_dir),
+            swift_sources: self.swift_sources.or(fallback.swift_sources),
+            swift_resources: self.swift_resources.or(fallback.swift_resources),
+            distribution: self.distribution.or(fallback.distribution),
+            url_template: self.url_template.or(fallback.url_template),
+            bundle_identifier: self.bundle_identifier.or(fallback.bundle_identifier),
+            exclude_arch: self.exclude_arch.or(fallback.exclude_arch),
+            jobs: self.jobs.or(fallback.jobs),
+            keep_backup: self.keep_backup.or(fallback.keep_backup),
+            reproducible: self.reproducible.or(fallback.reproducible),
+            target_settings,
+            manifest: self.manifest.or(fallback.manifest),
+            profile_set: self.profile_set.or(fallback.profile_set),
+        }
+    }
+
+    /// Resolves relative paths in these options against the given directory
+    pub(crate) fn relative_to(mut self, dir: &Path) -> Self {
+        self.privacy_manifest = self.privacy_manifest.map(|p| dir.join(p));
+        self.output_dir = self.output_dir.map(|p| dir.join(p));
+        self.swift_sources = self.swift_sources.map(|p| dir.join(p));
+        self.swift_resources = self.swift_resources.map(|p| dir.join(p));
+        self
+    }
+}
+
+/// Selects the crates of a workspace that should be packaged
+#[derive(Debug, Clone, Default)]
+pub struct CrateSelection {
+    /// Path to the Cargo.toml of the crate or workspace. Defaults to searching the current directory.
+    pub manifest_path: Option<PathBuf>,
+    /// Package specification (`name` or `name@version`) of the workspace member to package
+    pub package: Option<String>,
+    /// Package all workspace members whose library depends on uniffi
+    pub workspace: bool,
+}
+
+/// Flags that are passed to every cargo invocation
+#[derive(Debug, Clone, Default)]
+pub struct CargoOptions {
+    /// Run without accessing the network
+    pub offline: bool,
+    /// Require Cargo.lock to be up to date
+    pub locked: bool,
+    /// Require Cargo.lock and cache to be up to date
+    pub frozen: bool,
+    /// Additional arguments passed to every `cargo build`
+    pub build_args: Vec<String>,
+}
+
+impl CargoOptions {
+    pub(crate) fn args(&self) -> Vec<String> {
+        [
+            (self.offline, "--offline"),
+            (self.locked, "--locked"),
+            (self.frozen, "--frozen"),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, arg)| arg.to_owned())
+        .collect()
+    }
 }
 
-#[allow(clippy::too_many_arguments)]
 pub fn run(
-    platforms: Option<Vec<PlatformSpec>>,
-    build_target: Option<&str>,
-    package_name: Option<String>,
-    xcframework_name: Option<String>,
-    disable_warnings: bool,
+    options: PackageOptions,
+    selection: CrateSelection,
+    cargo_options: CargoOptions,
+    save: bool,
+    dry_run: bool,
+    verify: bool,
     config: Config,
-    mode: Mode,
-    lib_type_arg: LibTypeArg,
-    features: FeatureOptions,
-    skip_toolchains_check: bool,
-    swift_tools_version: &str,
-    privacy_manifest: Option<PathBuf>,
-    bundle_identifier: Option<String>,
-    exclude_arch: Vec<String>,
 ) -> Result<()> {
     // Show deprecation warning if --xcframework-name is used
-    if xcframework_name.is_some() {
+    if options.xcframework_name.is_some() {
         warning!(
             &config,
             "The --xcframework-name flag is deprecated and will be removed in a future release. \
@@ -82,32 +356,38 @@ pub fn run(
         );
     }
 
-    // TODO: Allow path as optional argument to take other directories than current directory
-    // let crates = metadata().uniffi_crates();
-    let crates = [metadata()
-        .current_crate()
-        .ok_or("Current directory is not part of a crate!")?];
+    let metadata = load_metadata(selection.manifest_path.as_deref(), &cargo_options)?;
+
+    let crates = if selection.workspace {
+        metadata.uniffi_crates()
+    } else if let Some(spec) = &selection.package {
+        vec![metadata.find_crate(spec)?]
+    } else if let Some(manifest_path) = &selection.manifest_path {
+        vec![metadata.crate_at(manifest_path)?]
+    } else {
+        vec![metadata.current_crate()?.ok_or(
+            "Current directory is not part of a crate! Select a crate with --package or --manifest-path, or package all crates with --workspace.",
+        )?]
+    };
 
-    if crates.len() == 1 {
+    if crates.is_empty() {
+        Err("No crate in the current workspace depends on uniffi!")?;
+    } else if crates.len() == 1 {
         return run_for_crate(
+            &metadata,
             crates[0],
-            platforms.clone(),
-            build_target,
-            package_name,
-            xcframework_name,
-            disable_warnings,
+            options,
+            &cargo_options,
+            save,
+            dry_run,
+            verify,
+            None,
             &config,
-            mode,
-            lib_type_arg,
-            features,
-            skip_toolchains_check,
-            swift_tools_version,
-            privacy_manifest.as_deref(),
-            bundle_identifier,
-            &exclude_arch,
         );
-    } else if package_name.is_some() {
+    } else if options.package_name.is_some() {
         Err("Package name can only be specified when building a single crate!")?;
+    } else if options.bundle_identifier.is_some() {
+        Err("Bundle identifier can only be specified when building a single crate!")?;
     }
 
     crates
@@ -115,22 +395,22 @@ pub fn run(
         .map(|current_crate| {
             info!(&config, "Packaging crate {}", current_crate.name);
             run_for_crate(
+                &metadata,
                 current_crate,
-                platforms.clone(),
-                build_target,
+                options.clone(),
+                &cargo_options,
+                save,
+                dry_run,
+                verify,
                 None,
-                xcframework_name.clone(),
-                disable_warnings,
                 &config,
-                mode,
-                lib_type_arg.clone(),
-                features.clone(),
-                skip_toolchains_check,
-                swift_tools_version,
-                privacy_manifest.as_deref(),
-                bundle_identifier.clone(),
-                &exclude_arch,
             )
+            .map_err(|e| {
+                Error::from(format!(
+                    "Failed to package crate {}: \n{e}\n",
+                    current_crate.name
+                ))
+            })
         })
         .filter_map(|result| result.err())
         .collect::<Errors>()
@@ -139,27 +419,103 @@ pub fn run(
 
 #[allow(clippy::too_many_arguments)]
 fn run_for_crate(
+    metadata: &Metadata,
     current_crate: &Package,
-    platforms: Option<Vec<PlatformSpec>>,
-    build_target: Option<&str>,
-    package_name: Option<String>,
-    xcframework_name: Option<String>,
-    disable_warnings: bool,
+    options: PackageOptions,
+    cargo_options: &CargoOptions,
+    save: bool,
+    dry_run: bool,
+    verify: bool,
+    target_dir: Option<&Utf8Path>,
     config: &Config,
-    mode: Mode,
-    lib_type_arg: LibTypeArg,
-    features: FeatureOptions,
-    skip_toolchains_check: bool,
-    swift_tools_version: &str,
-    privacy_manifest: Option<&Path>,
-    bundle_identifier: Option<String>,
-    exclude_arch: &[String],
 ) -> Result<()> {
-    let lib = current_crate
-        .targets
-        .iter()
-        .find(|t| t.kind.contains(&TargetKind::Lib))
-        .ok_or("No library tag defined in Cargo.toml!")?;
+    let options_to_save = save.then(|| options.clone());
+    let profile_set = options.profile_set.clone();
+    let options = options.or(metadata.swiftpackage_options(current_crate, profile_set.as_deref())?);
+    let options_to_rebuild = verify.then(|| options.clone());
+    let PackageOptions {
+        platforms,
+        target: build_target,
+        package_name,
+        xcframework_name,
+        suppress_warnings: disable_warnings,
+        release,
+        profile,
+        lib_type: lib_type_arg,
+        skip_toolchains_check,
+        features,
+        swift_tools_version,
+        privacy_manifest,
+        output_dir,
+        swift_sources,
+        swift_resources,
+        distribution,
+        url_template,
+        bundle_identifier,
+        exclude_arch,
+        jobs,
+        keep_backup,
+        reproducible,
+        target_settings,
+        mut manifest,
+        profile_set: _,
+    } = options;
+    let mode = match profile {
+        Some(profile) => Mode::Profile(profile),
+        None if release.unwrap_or_default() => Mode::Release,
+        None => Mode::Debug,
+    };
+    let disable_warnings = disable_warnings.unwrap_or_default();
+    let skip_toolchains_check = skip_toolchains_check.unwrap_or_default();
+    let lib_type_arg = lib_type_arg.unwrap_or(LibTypeArg::Automatic);
+    let swift_tools_version = swift_tools_version
+        .as_deref()
+        .unwrap_or(DEFAULT_SWIFT_TOOLS_VERSION);
+    let tools_version: Version = swift_tools_version
+        .parse()
+        .map_err(|e| format!("Invalid swift-tools-version: {e}"))?;
+    let privacy_manifest = privacy_manifest.as_deref();
+    let swift_sources = swift_sources.as_deref();
+    let swift_resources = swift_resources.as_deref();
+    for (dir, kind) in [
+        (swift_sources, "Swift sources"),
+        (swift_resources, "Swift resources"),
+    ] {
+        if let Some(dir) = dir.filter(|dir| !dir.is_dir()) {
+            Err(format!(
+                "{kind} directory {} does not exist!",
+                dir.display()
+            ))?;
+        }
+    }
+    if swift_resources.is_some() && tools_version < Version::new(5, 3) {
+        Err("Swift resources require a swift-tools-version of 5.3 or newer!")?;
+    }
+    // Rendered again once the native libraries of static libraries are known, but checked before
+    // building anything
+    manifest
+        .swift_code(&tools_version)
+        .map_err(|e| format!("Invalid manifest settings: \n {e}"))?;
+    let keep_backup = keep_backup.unwrap_or_default();
+    let exclude_arch = exclude_arch.unwrap_or_default();
+    let source_date_epoch = (reproducible.unwrap_or_default() || verify)
+        .then(source_date_epoch)
+        .transpose()?;
+    let distribution = distribution.unwrap_or_default();
+    let url_template = match distribution {
+        Distribution::Local => None,
+        Distribution::Remote => {
+            let url_template =
+                url_template.ok_or("Remote distribution requires a --url-template!")?;
+            // Fail on unknown placeholders before building anything
+            expand_url_template(&url_template, "", "")?;
+            Some(url_template)
+        }
+    };
+    // Parallel builds need a target dir per slice, which costs compile time and disk space
+    let jobs = jobs.unwrap_or(NonZeroUsize::MIN);
+
+    let lib = library_target(current_crate).ok_or("No library tag defined in Cargo.toml!")?;
     let lib_types = lib
         .crate_types
         .iter()
@@ -190,7 +546,7 @@ fn run_for_crate(
         .map(|p| p.target())
         .collect();
 
-    if let Some(build_target) = build_target {
+    if let Some(build_target) = build_target.as_deref() {
         targets.retain_mut(|platform_target| match platform_target {
             Target::Single { architecture, .. } => *architecture == build_target,
             Target::Universal {
@@ -258,7 +614,79 @@ fn run_for_crate(
         }
     }
 
+    // Platforms with the minimum versions that are written to Package.swift and used as
+    // deployment targets, the platforms given by the user are kept for saving
+    let package_platforms = resolve_platform_versions(&platforms, &targets, &tools_version)?;
+    let mut target_settings =
+        resolve_target_settings(&package_platforms, &target_settings, &features)?;
+
     let toolchain_targets = ToolchainTargets::query(&targets);
+    let target_dir = match target_dir {
+        Some(target_dir) => {
+            for settings in target_settings.values_mut() {
+                settings
+                    .env
+                    .insert("CARGO_TARGET_DIR".to_owned(), target_dir.to_string());
+            }
+            Cow::Borrowed(target_dir)
+        }
+        None => metadata.target_dir(),
+    };
+    // Concurrent cargo processes wait for each other on the lock of a shared target dir, so each
+    // target gets its own target dir if targets are built in parallel. The architectures of a
+    // universal target are built one after another and share it. A target dir passed to cargo
+    // explicitly is kept.
+    let explicit_target_dir = cargo_options
+        .build_args
+        .iter()
+        .any(|arg| arg.starts_with("--target-dir") || arg.contains("build.target-dir"));
+    if jobs.get() > 1 && targets.len() > 1 && explicit_target_dir {
+        warning!(
+            config,
+            "Targets built in parallel wait for each other, as they share the target dir passed to cargo"
+        );
+    } else if jobs.get() > 1 && targets.len() > 1 {
+        for target in &targets {
+            let build_dir = target_dir
+                .join("cargo-swift")
+                .join("build")
+                .join(target.library_identifier());
+            for arch in target.architectures() {
+                if let Some(settings) = target_settings.get_mut(arch) {
+                    settings
+                        .env
+                        .insert("CARGO_TARGET_DIR".to_owned(), build_dir.to_string());
+                }
+            }
+        }
+    }
+    if let Some(source_date_epoch) = source_date_epoch {
+        for settings in target_settings.values_mut() {
+            make_reproducible(
+                settings,
+                &metadata.workspace_root,
+                &target_dir,
+                source_date_epoch,
+            );
+        }
+    }
+    let cargo_config = CargoConfig::load()?;
+    for (arch, settings) in target_settings.iter_mut() {
+        inherit_rustflags(settings, arch, &cargo_config);
+    }
+    // Intermediate files are kept in the target dir, separately for every crate
+    let work_dir = target_dir
+        .join("cargo-swift")
+        .join(current_crate.name.as_str());
+    let generated_dir = work_dir.join("generated");
+    let package_dir = output_dir.unwrap_or_default().join(&package_name);
+    // Apps need to link the native libraries of a static library themselves, so they are
+    // reported by rustc and declared in Package.swift
+    let native_libs_dir =
+        Utf8PathBuf::try_from(std::path::absolute(work_dir.join("native-static-libs"))?)
+            .map_err(|e| format!("Invalid target directory: \n {e}"))?;
+    let native_static_libs = (lib_type == LibType::Static)
+        .then(|| NativeStaticLibs::new(&native_libs_dir, &mode, cargo_options, &target_settings));
 
     if !skip_toolchains_check {
         let missing_stable = check_stable_missing_targets(&targets, &toolchain_targets);
@@ -272,7 +700,15 @@ fn run_for_crate(
         ]
         .concat();
 
-        if !installation_required.is_empty() {
+        if dry_run {
+            if !installation_required.is_empty() {
+                info!(
+                    config,
+                    "The following toolchains are not installed and would be installed: {}",
+                    installation_required.join(", ")
+                );
+            }
+        } else if !installation_required.is_empty() {
             if config.accept_all || prompt_toolchain_installation(installation_required) {
                 install_toolchains(&missing_stable, config.silent)?;
                 if !missing_nightly_targets.is_empty() || !missing_nightly_src.is_empty() {
@@ -285,21 +721,106 @@ fn run_for_crate(
         }
     }
 
-    let crate_name = lib.name.replace('-', "_");
-    for target in &targets {
-        build_with_output(
-            target,
-            &crate_name,
-            mode,
+    if dry_run {
+        print_plan(
+            current_crate,
+            &package_name,
+            &package_dir,
+            &generated_dir,
+            &package_platforms,
+            &targets,
+            &target_dir,
+            &mode,
             lib_type,
-            config,
-            &features,
+            xcframework_name.as_deref(),
+            bundle_identifier.as_deref(),
+            cargo_options,
+            &target_settings,
             &toolchain_targets,
-        )?;
+            keep_backup,
+            url_template.as_deref(),
+            swift_sources,
+            swift_resources,
+            native_static_libs.as_ref(),
+        );
+        if let Some(options) = options_to_rebuild {
+            let rebuild_dir = work_dir.join("rebuild");
+            println!();
+            println!("To verify that the package is reproducible, it is then rebuilt in {rebuild_dir}/ and compared with {}/:", package_dir.display());
+            println!();
+            rebuild_package(
+                metadata,
+                current_crate,
+                PackageOptions {
+                    platforms: Some(package_platforms),
+                    package_name: Some(package_name),
+                    bundle_identifier,
+                    ..options
+                },
+                lib_type,
+                cargo_options,
+                &rebuild_dir,
+                true,
+                config,
+            )?;
+        }
+        return Ok(());
     }
 
-    let ffi_module_name =
-        generate_bindings_with_output(&targets, &crate_name, mode, lib_type, config)?;
+    if let Some(native_static_libs) = &native_static_libs {
+        fs::create_dir_all(native_static_libs.dir)
+            .map_err(|e| format!("Could not create {}: \n {e}", native_static_libs.dir))?;
+    }
+    let mut cache = StageCache::load(&target_dir, &current_crate.name);
+    let artifacts = build_with_output(
+        &targets,
+        current_crate,
+        &target_dir,
+        &mode,
+        lib_type,
+        config,
+        cargo_options,
+        &target_settings,
+        &toolchain_targets,
+        native_static_libs.as_ref(),
+        jobs,
+        &mut cache,
+    )?;
+    if let Some(native_static_libs) = &native_static_libs {
+        let detected = detect_native_libs(&targets, native_static_libs, config);
+        if !detected.is_empty() {
+            let names = detected
+                .frameworks
+                .iter()
+                .map(|framework| format!("{framework}.framework"))
+                .chain(
+                    detected
+                        .libraries
+                        .iter()
+                        .map(|library| format!("lib{library}")),
+                )
+                .join(", ");
+            info!(
+                config,
+                "Declaring native libraries of the static library in Package.swift: {names}"
+            );
+        }
+        manifest.linker_settings.extend(detected);
+    }
+    let manifest = manifest
+        .swift_code(&tools_version)
+        .map_err(|e| format!("Invalid manifest settings: \n {e}"))?;
+
+    let ffi_module_name = generate_bindings_with_output(
+        &targets,
+        &artifacts,
+        &target_settings,
+        &generated_dir,
+        &work_dir.join("bindings-check"),
+        &current_crate.manifest_path,
+        &mut cache,
+        config,
+    )?;
 
     // Use the FFI module name as the xcframework name by default
     let xcframework_name = xcframework_name.unwrap_or_else(|| ffi_module_name.clone());
@@ -313,32 +834,336 @@ fn run_for_crate(
         None
     };
 
-    recreate_output_dir(&package_name).expect("Could not create package output directory!");
-    create_xcframework_with_output(
-        &targets,
-        &crate_name,
-        &package_name,
-        &xcframework_name,
-        &ffi_module_name,
-        mode,
-        lib_type,
-        config,
-        privacy_manifest,
-        bundle_identifier.as_deref(),
-    )?;
-    create_package_with_output(
-        &package_name,
-        &xcframework_name,
-        disable_warnings,
-        &platforms,
-        swift_tools_version,
-        config,
-        privacy_manifest,
-    )?;
+    let url = url_template
+        .map(|template| {
+            let version = current_crate.version.to_string();
+            expand_url_template(&template, &xcframework_name, &version)
+        })
+        .transpose()?;
+
+    if let Some(options) = options_to_save {
+        let options = PackageOptions {
+            package_name: Some(package_name.clone()),
+            platforms: Some(platforms.clone()),
+            bundle_identifier: bundle_identifier.clone().or(options.bundle_identifier),
+            ..options
+        };
+        save_swiftpackage_options(
+            &current_crate.manifest_path,
+            profile_set.as_deref(),
+            &options,
+        )?;
+        info!(
+            config,
+            "Saved package options to {}", current_crate.manifest_path
+        );
+    }
+
+    // The stage cache is kept per crate, so a package in another output dir is never reused
+    let output_dir = std::path::absolute(&package_dir)?;
+    let mut fingerprint = FingerprintBuilder::new("xcframework");
+    fingerprint.value("output dir", output_dir.as_os_str().as_encoded_bytes());
+    for target in &targets {
+        let label = target.library_identifier();
+        let library_path = target.library_path(&artifacts, &target_dir, &mode)?;
+        fingerprint.file(&label, library_path.as_std_path())?;
+        let env_var = target.platform().deployment_target_env_var();
+        let settings = target_settings.get(target.architectures().first());
+        if let Some(deployment_target) = settings.and_then(|s| s.env.get(env_var)) {
+            fingerprint.value(&format!("{label} deployment target"), deployment_target);
+        }
+    }
+    fingerprint
+        .dir("headers", generated_dir.join("headers").as_std_path())?
+        .value("name", &xcframework_name)
+        .value("ffi module name", &ffi_module_name)
+        .value("mode", mode.to_string())
+        .value("lib type", lib_type.to_string())
+        .value("distribution", distribution.to_string())
+        .value(
+            "bundle identifier",
+            bundle_identifier.as_deref().unwrap_or_default(),
+        );
+    if let Some(privacy_manifest) = privacy_manifest {
+        fingerprint.file("privacy manifest", privacy_manifest)?;
+    }
+    let xcframework_fingerprint = fingerprint.finish();
+
+    let mut fingerprint = FingerprintBuilder::new("package");
+    fingerprint
+        .value("output dir", output_dir.as_os_str().as_encoded_bytes())
+        .value("name", &package_name)
+        .value("xcframework name", &xcframework_name)
+        .value("disable warnings", disable_warnings.to_string())
+        .value(
+            "reproducible",
+            source_date_epoch.map(|e| e.to_string()).unwrap_or_default(),
+        )
+        .value("swift tools version", swift_tools_version)
+        .value(
+            "platforms",
+            package_platforms
+                .iter()
+                .map(|p| p.package_swift(&tools_version))
+                .join(", "),
+        )
+        .dir("sources", generated_dir.join("sources").as_std_path())?
+        .value("url", url.as_deref().unwrap_or_default())
+        .value(
+            "manifest",
+            serde_json::to_vec(&manifest)
+                .map_err(|e| format!("Could not serialize manifest settings: \n {e}"))?,
+        );
+    if let Some(privacy_manifest) = privacy_manifest {
+        fingerprint.file("privacy manifest", privacy_manifest)?;
+    }
+    if let Some(swift_sources) = swift_sources {
+        fingerprint.dir("swift sources", swift_sources)?;
+    }
+    if let Some(swift_resources) = swift_resources {
+        fingerprint.dir("swift resources", swift_resources)?;
+    }
+    let package_fingerprint = fingerprint.finish();
+
+    // The package is assembled in a staging directory and only replaces the previous package
+    // once every step succeeded, so that a failing step never leaves a broken package behind
+    let xcframework_file = format!("{xcframework_name}.xcframework");
+    // For remote distribution, the package only contains the zipped XCFramework for upload
+    let xcframework_artifact = match distribution {
+        Distribution::Local => xcframework_file.clone(),
+        Distribution::Remote => format!("{xcframework_file}.zip"),
+    };
+    let xcframework_fresh = cache.is_fresh(
+        "XCFramework",
+        &xcframework_fingerprint,
+        &package_dir.join(&xcframework_artifact),
+    );
+    let package_fresh = cache.is_fresh(
+        "Swift package",
+        &package_fingerprint,
+        &package_dir.join("Package.swift"),
+    );
+    if xcframework_fresh && package_fresh {
+        info!(config, "Reused Swift package from the last run");
+    } else {
+        if !xcframework_fresh {
+            cache.invalidate("XCFramework")?;
+        }
+        cache.invalidate("Swift package")?;
+        let staging_dir = create_staging_dir(&package_dir)?;
+        let assembled = (|| {
+            let previous_artifact = package_dir.join(&xcframework_artifact);
+            let artifact = staging_dir.join(&xcframework_artifact);
+            if xcframework_fresh {
+                info!(config, "Reused XCFramework from the last run");
+                match distribution {
+                    Distribution::Local => copy_dir(previous_artifact, &artifact),
+                    Distribution::Remote => fs::copy(previous_artifact, &artifact)
+                        .map(|_| ())
+                        .map_err(Into::into),
+                }
+                .map_err(|e| format!("Could not copy XCFramework of the last run: \n {e}"))?;
+            } else {
+                create_xcframework_with_output(
+                    &targets,
+                    &artifacts,
+                    &target_dir,
+                    generated_dir.as_std_path(),
+                    &staging_dir,
+                    &xcframework_name,
+                    &ffi_module_name,
+                    &mode,
+                    lib_type,
+                    &target_settings,
+                    config,
+                    privacy_manifest,
+                    bundle_identifier.as_deref(),
+                )?;
+                if distribution == Distribution::Remote {
+                    let xcframework_dir = staging_dir.join(&xcframework_file);
+                    run_step(config, "Zipping XCFramework...", || {
+                        zip_dir(&xcframework_dir, &artifact)?;
+                        fs::remove_dir_all(&xcframework_dir).map_err(Into::into)
+                    })?;
+                }
+            }
+            let remote = url
+                .clone()
+                .map(|url| -> Result<_> {
+                    let checksum = file_checksum(&artifact).map_err(|e| {
+                        format!("Could not compute checksum of {xcframework_artifact}: \n {e}")
+                    })?;
+                    Ok(RemoteBinaryTarget { url, checksum })
+                })
+                .transpose()?;
+            create_package_with_output(
+                &package_name,
+                &staging_dir,
+                generated_dir.join("sources").as_std_path(),
+                &xcframework_name,
+                disable_warnings,
+                &package_platforms,
+                swift_tools_version,
+                config,
+                privacy_manifest,
+                remote.as_ref(),
+                swift_sources,
+                swift_resources,
+                &manifest,
+            )?;
+            if let Some(source_date_epoch) = source_date_epoch {
+                normalize_mtimes(&staging_dir, source_date_epoch)?;
+            }
+
+            Ok(())
+        })();
+        if let Err(e) = assembled {
+            remove_staging_dir(&staging_dir)?;
+            return Err(e);
+        }
+        replace_output_dir(&staging_dir, &package_dir, keep_backup)?;
+
+        cache.record("XCFramework", &xcframework_fingerprint)?;
+        cache.record("Swift package", &package_fingerprint)?;
+
+        let spinner = config.silent.not().then(|| {
+            MainSpinner::with_message(format!(
+                "Successfully created Swift Package in '{}/'!",
+                package_dir.display()
+            ))
+        });
+        spinner.finish();
+        if keep_backup {
+            info!(
+                config,
+                "Kept the previous package in '{}.bak/'",
+                package_dir.display()
+            );
+        }
+    }
+
+    if let Some(options) = options_to_rebuild {
+        let rebuild_dir = work_dir.join("rebuild");
+        remove_dir_if_exists(&rebuild_dir)
+            .map_err(|e| format!("Could not remove {rebuild_dir}: \n {e}"))?;
+        info!(
+            config,
+            "Rebuilding crate {} in {rebuild_dir} to verify that the package is reproducible",
+            current_crate.name
+        );
+        rebuild_package(
+            metadata,
+            current_crate,
+            PackageOptions {
+                platforms: Some(package_platforms),
+                package_name: Some(package_name.clone()),
+                bundle_identifier,
+                ..options
+            },
+            lib_type,
+            cargo_options,
+            &rebuild_dir,
+            false,
+            config,
+        )?;
+
+        let rebuilt_dir = rebuild_dir.join("package").join(&package_name);
+        let differences = differences(
+            &checksums(&package_dir)?,
+            &checksums(rebuilt_dir.as_std_path())?,
+        );
+        if !differences.is_empty() {
+            Err(format!(
+                "The package is not reproducible! These files differ between {} and the rebuild in {rebuilt_dir}: \n {}",
+                package_dir.display(),
+                differences.join("\n ")
+            ))?;
+        }
+        info!(
+            config,
+            "Verified that a rebuild produces an identical package"
+        );
+    }
 
     Ok(())
 }
 
+/// Packages the crate again with the resolved `options` of a run into `rebuild_dir`. A separate
+/// target dir makes cargo build everything again.
+#[allow(clippy::too_many_arguments)]
+fn rebuild_package(
+    metadata: &Metadata,
+    current_crate: &Package,
+    options: PackageOptions,
+    lib_type: LibType,
+    cargo_options: &CargoOptions,
+    rebuild_dir: &Utf8Path,
+    dry_run: bool,
+    config: &Config,
+) -> Result<()> {
+    let options = PackageOptions {
+        lib_type: Some(match lib_type {
+            LibType::Static => LibTypeArg::Static,
+            LibType::Dynamic => LibTypeArg::Dynamic,
+        }),
+        output_dir: Some(rebuild_dir.join("package").into()),
+        keep_backup: None,
+        reproducible: Some(true),
+        ..options
+    };
+    run_for_crate(
+        metadata,
+        current_crate,
+        options,
+        cargo_options,
+        false,
+        dry_run,
+        false,
+        Some(&rebuild_dir.join("target")),
+        config,
+    )
+    .map_err(|e| format!("Could not rebuild the package: \n {e}").into())
+}
+
+/// Replaces the `{name}` and `{version}` placeholders in the URL template of a remote binary
+/// target
+fn expand_url_template(template: &str, name: &str, version: &str) -> Result<String> {
+    let url = template
+        .replace("{name}", name)
+        .replace("{version}", version);
+    if url.contains(['{', '}']) {
+        Err(format!(
+            "Invalid url-template `{template}`: only {{name}} and {{version}} can be used as placeholders"
+        ))?;
+    }
+
+    Ok(url)
+}
+
+/// Runs a packaging stage unless its fingerprint matches the last successful run and its
+/// output still exists
+fn run_stage(
+    cache: &mut StageCache,
+    stage: &str,
+    fingerprint: &Fingerprint,
+    output: &Path,
+    config: &Config,
+    run: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    if cache.is_fresh(stage, fingerprint, output) {
+        info!(config, "Reused {stage} from the last run");
+        return Ok(());
+    }
+
+    cache.invalidate(stage)?;
+    run()?;
+    cache.record(stage, fingerprint)
+}
+
+/// Major and minor version of a `SupportedPlatform` constant and of the swift-tools-version that
+/// introduced it
+type VersionConstant = ((u32, u32), (u32, u32));
+
 // FIXME: This can be removed once variant_count is stabilized: https://doc.rust-lang.org/std/mem/fn.variant_count.html#:~:text=Function%20std%3A%3Amem%3A%3Avariant_count&text=Returns%20the%20number%20of%20variants,the%20return%20value%20is%20unspecified.
 const PLATFORM_COUNT: usize = 5;
 
@@ -366,6 +1191,156 @@ impl Platform {
         }
     }
 
+    /// Returns the identifier of this platform as used in `--platforms` and Cargo.toml
+    fn name(&self) -> String {
+        self.to_possible_value()
+            .expect("Platform variants should not be skipped")
+            .get_name()
+            .to_owned()
+    }
+
+    /// Returns the Rust target triples of all architectures built for this platform
+    fn architectures(self) -> Vec<&'static str> {
+        self.into_apple_platforms()
+            .into_iter()
+            .flat_map(|p| p.target().architectures())
+            .collect()
+    }
+
+    /// Returns the name of this platform in `SupportedPlatform` of Package.swift
+    fn swift_name(&self) -> &'static str {
+        match self {
+            Platform::Macos => "macOS",
+            Platform::Ios => "iOS",
+            Platform::Tvos => "tvOS",
+            Platform::Watchos => "watchOS",
+            Platform::Visionos => "visionOS",
+            Platform::Maccatalyst => "macCatalyst",
+        }
+    }
+
+    /// Minimum version used when none is given and all built Rust targets support it
+    fn default_min_version(&self) -> Version {
+        match self {
+            Platform::Macos => Version::new(10, 15),
+            Platform::Ios | Platform::Tvos | Platform::Maccatalyst => Version::new(13, 0),
+            Platform::Watchos => Version::new(6, 0),
+            Platform::Visionos => Version::new(1, 0),
+        }
+    }
+
+    /// Returns the swift-tools-version that introduced this platform in Package.swift
+    fn required_swift_tools_version(&self) -> Version {
+        match self {
+            Platform::Macos | Platform::Ios | Platform::Tvos | Platform::Watchos => {
+                Version::new(5, 0)
+            }
+            Platform::Maccatalyst => Version::new(5, 5),
+            Platform::Visionos => Version::new(5, 9),
+        }
+    }
+
+    /// Returns the oldest version of this platform the given swift-tools-version supports
+    fn oldest_supported_version(&self, swift_tools_version: &Version) -> Version {
+        // PackageDescription 5.9 deprecated the oldest versions of each platform
+        let is_5_9 = *swift_tools_version >= Version::new(5, 9);
+        match self {
+            Platform::Macos if is_5_9 => Version::new(10, 13),
+            Platform::Macos => Version::new(10, 10),
+            Platform::Ios | Platform::Tvos if is_5_9 => Version::new(12, 0),
+            Platform::Ios => Version::new(8, 0),
+            Platform::Tvos => Version::new(9, 0),
+            Platform::Watchos if is_5_9 => Version::new(4, 0),
+            Platform::Watchos => Version::new(2, 0),
+            Platform::Maccatalyst => Version::new(13, 0),
+            Platform::Visionos => Version::new(1, 0),
+        }
+    }
+
+    /// Returns the name of the `SupportedPlatform` constant for `version`, e.g. `v15` or
+    /// `v10_15`, if the given swift-tools-version declares one
+    fn version_constant(&self, version: &Version, swift_tools_version: &Version) -> Option<String> {
+        // Versions with a constant and the swift-tools-version that introduced it
+        let constants: &[VersionConstant] = match self {
+            Platform::Macos => &[
+                ((10, 10), (5, 0)),
+                ((10, 11), (5, 0)),
+                ((10, 12), (5, 0)),
+                ((10, 13), (5, 0)),
+                ((10, 14), (5, 0)),
+                ((10, 15), (5, 0)),
+                ((11, 0), (5, 3)),
+                ((12, 0), (5, 5)),
+                ((13, 0), (5, 7)),
+                ((14, 0), (5, 9)),
+                ((15, 0), (6, 0)),
+                ((26, 0), (6, 2)),
+            ],
+            Platform::Ios => &[
+                ((8, 0), (5, 0)),
+                ((9, 0), (5, 0)),
+                ((10, 0), (5, 0)),
+                ((11, 0), (5, 0)),
+                ((12, 0), (5, 0)),
+                ((13, 0), (5, 0)),
+                ((14, 0), (5, 3)),
+                ((15, 0), (5, 5)),
+                ((16, 0), (5, 7)),
+                ((17, 0), (5, 9)),
+                ((18, 0), (6, 0)),
+                ((26, 0), (6, 2)),
+            ],
+            Platform::Tvos => &[
+                ((9, 0), (5, 0)),
+                ((10, 0), (5, 0)),
+                ((11, 0), (5, 0)),
+                ((12, 0), (5, 0)),
+                ((13, 0), (5, 0)),
+                ((14, 0), (5, 3)),
+                ((15, 0), (5, 5)),
+                ((16, 0), (5, 7)),
+                ((17, 0), (5, 9)),
+                ((18, 0), (6, 0)),
+                ((26, 0), (6, 2)),
+            ],
+            Platform::Watchos => &[
+                ((2, 0), (5, 0)),
+                ((3, 0), (5, 0)),
+                ((4, 0), (5, 0)),
+                ((5, 0), (5, 0)),
+                ((6, 0), (5, 0)),
+                ((7, 0), (5, 3)),
+                ((8, 0), (5, 5)),
+                ((9, 0), (5, 7)),
+                ((10, 0), (5, 9)),
+                ((11, 0), (6, 0)),
+                ((26, 0), (6, 2)),
+            ],
+            Platform::Maccatalyst => &[
+                ((13, 0), (5, 5)),
+                ((14, 0), (5, 5)),
+                ((15, 0), (5, 5)),
+                ((16, 0), (5, 7)),
+                ((17, 0), (5, 9)),
+                ((18, 0), (6, 0)),
+                ((26, 0), (6, 2)),
+            ],
+            Platform::Visionos => &[((1, 0), (5, 9)), ((2, 0), (6, 0)), ((26, 0), (6, 2))],
+        };
+
+        let (_, (tools_major, tools_minor)) = constants
+            .iter()
+            .find(|((major, minor), _)| version.major == *major && version.minor == *minor)?;
+        if version.patch != 0 || *swift_tools_version < Version::new(*tools_major, *tools_minor) {
+            return None;
+        }
+
+        match version.minor {
+            0 => Some(format!("v{}", version.major)),
+            minor => Some(format!("v{}_{minor}", version.major)),
+        }
+    }
+
     fn display_name(&self) -> String {
         let name = match self {
             Platform::Macos => "macOS",
@@ -404,26 +1379,77 @@ impl Platform {
     }
 }
 
-#[derive(Debug, Clone, Args)]
+#[derive(Debug, Clone, Args, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
 pub struct PlatformSpec {
     pub platform: Platform,
-    pub min_version: Option<String>,
+    pub min_version: Option<Version>,
 }
 
 impl PlatformSpec {
-    pub(crate) fn package_swift(&self) -> String {
-        let v = self.min_version.as_deref();
-        match self.platform {
-            Platform::Macos => format!(".macOS(.v{})", v.unwrap_or("10_15")),
-            Platform::Ios => format!(".iOS(.v{})", v.unwrap_or("13")),
-            Platform::Tvos => format!(".tvOS(.v{})", v.unwrap_or("13")),
-            Platform::Watchos => format!(".watchOS(.v{})", v.unwrap_or("6")),
-            Platform::Visionos => format!(".visionOS(.v{})", v.unwrap_or("1")),
-            Platform::Maccatalyst => format!(".macCatalyst(.v{})", v.unwrap_or("13")),
+    /// Returns the entry for the `platforms` of Package.swift, e.g. `.iOS(.v15)`. Versions
+    /// without a constant in the given swift-tools-version are written as string,
+    /// e.g. `.iOS("13.4")`.
+    pub(crate) fn package_swift(&self, swift_tools_version: &Version) -> String {
+        let version = self
+            .min_version
+            .unwrap_or_else(|| self.platform.default_min_version());
+        let name = self.platform.swift_name();
+        match self
+            .platform
+            .version_constant(&version, swift_tools_version)
+        {
+            Some(constant) => format!(".{name}(.{constant})"),
+            None => format!(".{name}(\"{version}\")"),
         }
     }
 }
 
+impl std::str::FromStr for PlatformSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (platform_str, min_version) = match s.split_once('@') {
+            Some((p, v)) => (p, Some(v)),
+            None => (s, None),
+        };
+
+        let platform = Platform::from_str(platform_str, true)
+            .map_err(|_| format!("invalid platform `{}`", platform_str))?;
+        let min_version = min_version.map(str::parse).transpose()?;
+
+        Ok(PlatformSpec {
+            platform,
+            min_version,
+        })
+    }
+}
+
+impl Display for PlatformSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.platform.name())?;
+        if let Some(min_version) = &self.min_version {
+            write!(f, "@{min_version}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<PlatformSpec> for String {
+    fn from(value: PlatformSpec) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for PlatformSpec {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
 #[derive(Clone)]
 pub struct PlatformSpecParser;
 
@@ -436,24 +1462,10 @@ impl TypedValueParser for PlatformSpecParser {
         _arg: Option<&clap::Arg>,
         value: &std::ffi::OsStr,
     ) -> clap::error::Result<Self::Value> {
-        let s = value.to_string_lossy();
-
-        let (platform_str, min_version) = match s.split_once('@') {
-            Some((p, v)) => (p, Some(v.to_string())),
-            None => (s.as_ref(), None),
-        };
-
-        let platform = Platform::from_str(platform_str, true).map_err(|_| {
-            clap::error::Error::raw(
-                clap::error::ErrorKind::InvalidValue,
-                format!("invalid platform `{}`", platform_str),
-            )
-        })?;
-
-        Ok(PlatformSpec {
-            platform,
-            min_version,
-        })
+        value
+            .to_string_lossy()
+            .parse()
+            .map_err(|e| clap::error::Error::raw(clap::error::ErrorKind::InvalidValue, e))
     }
 
     fn possible_values(&self) -> Option<Box<dyn Iterator<Item = clap::builder::PossibleValue>>> {
@@ -499,6 +1511,121 @@ fn prompt_platforms(accept_all: bool) -> Vec<PlatformSpec> {
         .collect()
 }
 
+/// Resolves the minimum version of every platform. Platforms without a version get their default
+/// version, raised to the lowest version that Package.swift and rustc support for the platform.
+/// Explicit versions are checked against the swift-tools-version and rustc.
+fn resolve_platform_versions(
+    platforms: &[PlatformSpec],
+    targets: &[Target],
+    swift_tools_version: &Version,
+) -> Result<Vec<PlatformSpec>> {
+    platforms
+        .iter()
+        .map(|spec| {
+            let platform = spec.platform;
+            let name = platform.swift_name();
+            let required_tools_version = platform.required_swift_tools_version();
+            if *swift_tools_version < required_tools_version {
+                Err(format!(
+                    "{name} requires swift-tools-version {required_tools_version} or later, but {swift_tools_version} is used!"
+                ))?;
+            }
+
+            let apple_platforms = platform.into_apple_platforms();
+            let rust_minimums: Vec<_> = targets
+                .iter()
+                .filter(|target| apple_platforms.contains(&target.platform()))
+                .flat_map(|target| {
+                    target.architectures().into_iter().map(move |arch| {
+                        (arch, target.platform().minimum_deployment_target(arch))
+                    })
+                })
+                .collect();
+            let oldest = platform.oldest_supported_version(swift_tools_version);
+
+            // Slices of Rust targets with a higher minimum are built for their minimum instead,
+            // e.g. aarch64-apple-darwin for macOS 11
+            let platform_minimum = rust_minimums.iter().map(|(_, minimum)| *minimum).min();
+
+            let Some(version) = spec.min_version else {
+                let version = platform_minimum
+                    .into_iter()
+                    .chain([platform.default_min_version(), oldest])
+                    .max();
+                return Ok(PlatformSpec {
+                    platform,
+                    min_version: version,
+                });
+            };
+
+            if version < oldest {
+                Err(format!(
+                    "Invalid minimum version {spec}: swift-tools-version {swift_tools_version} supports {name} {oldest} or later!"
+                ))?;
+            }
+            if let Some(minimum) = platform_minimum.filter(|minimum| version < *minimum) {
+                Err(format!(
+                    "Invalid minimum version {spec}: {name} {version} is below {minimum}, the lowest deployment target rustc supports for {name}! \
+                    Use {}@{minimum} or later.",
+                    platform.name()
+                ))?;
+            }
+
+            Ok(spec.clone())
+        })
+        .collect()
+}
+
+/// Resolves the `target-settings` that apply to each architecture of the given platforms,
+/// including the features to build it with. Settings for a Rust target triple are applied on top
+/// of the settings for its platform.
+fn resolve_target_settings(
+    platforms: &[PlatformSpec],
+    target_settings: &BTreeMap<String, TargetSettings>,
+    features: &FeatureOptions,
+) -> Result<HashMap<&'static str, TargetSettings>> {
+    for key in target_settings.keys() {
+        let is_known = Platform::value_variants()
+            .iter()
+            .any(|p| p.name() == *key || p.architectures().contains(&key.as_str()));
+        if !is_known {
+            Err(format!(
+                "Unknown platform or target `{key}` in target-settings! Use a platform name like `ios` or a Rust target triple like `aarch64-apple-ios`."
+            ))?;
+        }
+    }
+
+    let mut resolved = HashMap::new();
+    for spec in platforms {
+        let platform_settings = target_settings.get(&spec.platform.name());
+        for apple_platform in spec.platform.into_apple_platforms() {
+            let env_var = apple_platform.deployment_target_env_var();
+            for arch in apple_platform.target().architectures() {
+                let mut settings = TargetSettings::default();
+                settings.features.extend(features);
+                if let Some(platform_settings) = platform_settings {
+                    settings.merge(platform_settings);
+                }
+                if let Some(arch_settings) = target_settings.get(arch) {
+                    settings.merge(arch_settings);
+                }
+                // The deployment target matches the minimum version in Package.swift, unless rustc
+                // requires a higher one for the architecture, e.g. macOS 11 for aarch64
+                if let Some(min_version) = spec.min_version {
+                    let deployment_target =
+                        min_version.max(apple_platform.minimum_deployment_target(arch));
+                    settings
+                        .env
+                        .insert(env_var.to_owned(), deployment_target.to_string());
+                }
+                resolved.insert(arch, settings);
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
 /// Checks if toolchains for all tier 1/2 target architectures are installed on the
 /// default (stable) toolchain and returns a list of missing ones.
 fn check_stable_missing_targets(
@@ -687,6 +1814,155 @@ fn install_nightly_src(silent: bool) -> Result<()> {
     Ok(())
 }
 
+/// Prints everything `run_for_crate` would do for the resolved options, including every
+/// command it would run, without running any of them
+#[allow(clippy::too_many_arguments)]
+fn print_plan(
+    current_crate: &Package,
+    package_name: &str,
+    package_dir: &Path,
+    generated_dir: &Utf8Path,
+    platforms: &[PlatformSpec],
+    targets: &[Target],
+    target_dir: &Utf8Path,
+    mode: &Mode,
+    lib_type: LibType,
+    xcframework_name: Option<&str>,
+    bundle_identifier: Option<&str>,
+    cargo_options: &CargoOptions,
+    target_settings: &HashMap<&str, TargetSettings>,
+    toolchain_targets: &ToolchainTargets,
+    keep_backup: bool,
+    url_template: Option<&str>,
+    swift_sources: Option<&Path>,
+    swift_resources: Option<&Path>,
+    native_static_libs: Option<&NativeStaticLibs>,
+) {
+    // The FFI module name is only known after bindings were generated from the built library
+    let xcframework_name = xcframework_name.unwrap_or("<FFI module name>");
+    let mode_name = match mode {
+        Mode::Debug => "debug".to_owned(),
+        Mode::Release => "release".to_owned(),
+        Mode::Profile(profile) => format!("profile `{profile}`"),
+    };
+
+    let staging_dir = staging_dir(package_dir);
+    let package_dir = package_dir.display();
+
+    println!("Packaging plan for crate {}:", current_crate.name);
+    println!("  Swift package:  {package_name} ({package_dir}/)");
+    println!("  Platforms:      {}", platforms.iter().join(", "));
+    println!("  Library type:   {lib_type}");
+    println!("  Build mode:     {mode_name}");
+    match url_template {
+        Some(url_template) => {
+            println!("  XCFramework:    {package_dir}/{xcframework_name}.xcframework.zip");
+            println!("  Download URL:   {url_template}");
+        }
+        None => println!("  XCFramework:    {package_dir}/{xcframework_name}.xcframework"),
+    }
+    if lib_type == LibType::Dynamic {
+        let default_identifier = format!("com.cargo-swift.{xcframework_name}");
+        println!(
+            "  Bundle ID:      {}",
+            bundle_identifier.unwrap_or(&default_identifier)
+        );
+    }
+
+    println!();
+    println!("Targets:");
+    for target in targets {
+        let architectures = target
+            .architectures()
+            .into_iter()
+            .map(|arch| {
+                let toolchain = if toolchain_targets.needs_build_std(arch) {
+                    "nightly, build-std"
+                } else if toolchain_targets.use_nightly() {
+                    "nightly"
+                } else {
+                    "stable"
+                };
+                format!("{arch} ({toolchain})")
+            })
+            .join(", ");
+        println!(
+            "  {} [{}]: {architectures}",
+            target.display_name(),
+            target.library_identifier()
+        );
+    }
+
+    println!();
+    println!("Commands:");
+    for target in targets {
+        let commands = target.commands(
+            mode,
+            cargo_options,
+            target_settings,
+            toolchain_targets,
+            &current_crate.manifest_path,
+            native_static_libs,
+        );
+        for command in commands {
+            let env = command
+                .get_envs()
+                .filter_map(|(key, value)| {
+                    // Escapes the separators of CARGO_ENCODED_RUSTFLAGS
+                    Some(format!(
+                        "{}={} ",
+                        key.to_string_lossy(),
+                        value?.to_string_lossy().escape_debug()
+                    ))
+                })
+                .join("");
+            println!("  {env}{}", command.info());
+        }
+    }
+
+    println!();
+    println!("Steps:");
+    for target in targets {
+        if let Target::Universal { architectures, .. } = target {
+            println!(
+                "  Merge libraries for {} into {}",
+                architectures.iter().join(", "),
+                target.library_directory(target_dir, mode)
+            );
+        }
+    }
+    let staging_dir = staging_dir.display();
+    println!("  Generate Swift bindings into {generated_dir}/");
+    if let Some(native_static_libs) = native_static_libs {
+        println!(
+            "  Declare the native libraries listed in {}/ in Package.swift",
+            native_static_libs.dir
+        );
+    }
+    println!("  Create {staging_dir}/{xcframework_name}.xcframework");
+    if url_template.is_some() {
+        println!("  Zip it into {staging_dir}/{xcframework_name}.xcframework.zip");
+    }
+    println!("  Create Swift package in {staging_dir}/");
+    if let Some(swift_sources) = swift_sources {
+        println!(
+            "  Merge Swift sources from {} into Sources/{package_name}/",
+            swift_sources.display()
+        );
+    }
+    if let Some(swift_resources) = swift_resources {
+        println!(
+            "  Merge Swift resources from {} into Sources/{package_name}/Resources/",
+            swift_resources.display()
+        );
+    }
+    if keep_backup {
+        println!("  Replace {package_dir}/, keeping the previous package in {package_dir}.bak/");
+    } else {
+        println!("  Replace {package_dir}/");
+    }
+}
+
 fn prompt_package_name(crate_name: &str, accept_all: bool) -> String {
     let default = crate_name.to_case(Case::UpperCamel);
 
@@ -741,79 +2017,214 @@ fn pick_lib_type(
     Ok(choosen)
 }
 
+/// Reads the native frameworks and libraries that rustc reported for the static library of every
+/// architecture
+fn detect_native_libs(
+    targets: &[Target],
+    native_static_libs: &NativeStaticLibs,
+    config: &Config,
+) -> LinkerSettings {
+    let mut detected = LinkerSettings::default();
+    for arch in targets.iter().flat_map(Target::architectures) {
+        let path = native_static_libs.path(arch);
+        match fs::read_to_string(&path) {
+            Ok(native_libs) => {
+                let (settings, unknown) = LinkerSettings::from_native_static_libs(&native_libs);
+                if !unknown.is_empty() {
+                    warning!(
+                        config,
+                        "Ignoring unknown native link arguments of {arch}: {}",
+                        unknown.join(" ")
+                    );
+                }
+                detected.extend(settings);
+            }
+            Err(e) => warning!(
+                config,
+                "Could not read the native libraries of the static library for {arch} from {path}: {e}\n Run `cargo clean` to rebuild the library and detect them again."
+            ),
+        }
+    }
+
+    detected
+}
+
+#[allow(clippy::too_many_arguments)]
 fn generate_bindings_with_output(
     targets: &[Target],
-    lib_name: &str,
-    mode: Mode,
-    lib_type: LibType,
+    artifacts: &Artifacts,
+    target_settings: &HashMap<&str, TargetSettings>,
+    generated_dir: &Utf8Path,
+    check_dir: &Utf8Path,
+    manifest_path: &Utf8Path,
+    cache: &mut StageCache,
     config: &Config,
 ) -> Result<String> {
-    run_step(config, "Generating Swift bindings...", || {
-        let lib_file = library_file_name(lib_name, lib_type);
-        let target = metadata().target_dir();
-        let archs = targets
-            .first()
-            .ok_or("Could not generate UniFFI bindings: No target platform selected!")?
-            .architectures();
-        let arch = archs.first();
-        let lib_path: Utf8PathBuf = format!("{target}/{arch}/{mode}/{lib_file}").into();
-
-        generate_bindings(&lib_path)
-            .map_err(|e| format!("Could not generate UniFFI bindings for udl files due to the following error: \n {e}").into())
-    })
+    let archs: Vec<_> = targets.iter().flat_map(Target::architectures).collect();
+    let first = *archs
+        .first()
+        .ok_or("Could not generate UniFFI bindings: No target platform selected!")?;
+    let features_of = |arch| target_settings.get(arch).map(|s| &s.features);
+
+    let mut fingerprint = FingerprintBuilder::new("bindings");
+    for &arch in &archs {
+        fingerprint.file(arch, artifacts.library(arch)?.as_std_path())?;
+        let features = features_of(arch).map(ToString::to_string);
+        fingerprint.value(&format!("{arch} features"), features.unwrap_or_default());
+    }
+    if let Some(crate_dir) = manifest_path.parent() {
+        fingerprint.file("uniffi.toml", crate_dir.join("uniffi.toml").as_std_path())?;
+    }
+
+    let generate = || {
+        run_step(config, "Generating Swift bindings...", || {
+            generate_bindings(artifacts.library(first)?, generated_dir)
+                .map_err(|e| format!("Could not generate UniFFI bindings for udl files due to the following error: \n {e}"))?;
+
+            // Bindings are generated from a single library, so libraries built with other
+            // features must not export a different API
+            let mut checked = vec![features_of(first)];
+            for &arch in &archs {
+                let features = features_of(arch);
+                if checked.contains(&features) {
+                    continue;
+                }
+                checked.push(features);
+
+                let check_dir = check_dir.join(arch);
+                generate_bindings(artifacts.library(arch)?, &check_dir)?;
+                if !same_bindings(generated_dir, &check_dir)? {
+                    let describe = |features: Option<&FeatureOptions>| {
+                        features.map(ToString::to_string).unwrap_or_default()
+                    };
+                    Err(format!(
+                    "The Swift bindings for {arch} ({}) differ from the bindings for {first} ({})! \
+                    All slices of the XCFramework share the same bindings, so features that change the exported API must be enabled for every platform.",
+                    describe(features),
+                    describe(features_of(first)),
+                ))?;
+                }
+            }
+
+            Ok(())
+        })
+    };
+    run_stage(
+        cache,
+        "Swift bindings",
+        &fingerprint.finish(),
+        generated_dir.join("headers").as_std_path(),
+        config,
+        generate,
+    )?;
+
+    ffi_module_name(generated_dir)
 }
 
+#[allow(clippy::too_many_arguments)]
+/// Builds all targets and returns the libraries cargo reported for each architecture
 fn build_with_output(
-    target: &Target,
-    lib_name: &str,
-    mode: Mode,
+    targets: &[Target],
+    current_crate: &Package,
+    target_dir: &Utf8Path,
+    mode: &Mode,
     lib_type: LibType,
     config: &Config,
-    features: &FeatureOptions,
+    cargo_options: &CargoOptions,
+    target_settings: &HashMap<&str, TargetSettings>,
     toolchain_targets: &ToolchainTargets,
-) -> Result<()> {
-    let mut commands = target.commands(lib_name, mode, lib_type, features, toolchain_targets);
-    for command in &mut commands {
-        command.env("CARGO_TERM_COLOR", "always");
-    }
+    native_static_libs: Option<&NativeStaticLibs>,
+    jobs: NonZeroUsize,
+    cache: &mut StageCache,
+) -> Result<Artifacts> {
+    let commands = targets
+        .iter()
+        .map(|target| {
+            let mut commands = target.commands(
+                mode,
+                cargo_options,
+                target_settings,
+                toolchain_targets,
+                &current_crate.manifest_path,
+                native_static_libs,
+            );
+            for command in &mut commands {
+                command.env("CARGO_TERM_COLOR", "always");
+            }
+            commands
+        })
+        .collect();
+    let target_names = targets.iter().map(Target::display_name).join(", ");
 
-    run_step_with_commands(
+    let outputs = run_step_with_concurrent_commands(
         config,
-        format!("Building target {}", target.display_name()),
-        &mut commands,
+        format!("Building targets {target_names}"),
+        commands,
+        jobs,
     )?;
 
-    Ok(())
+    let mut artifacts = Artifacts::default();
+    for (target, outputs) in targets.iter().zip(outputs) {
+        for (arch, output) in target.architectures().into_iter().zip(outputs) {
+            artifacts.add(arch, &output, &current_crate.id, lib_type)?;
+        }
+    }
+
+    for target in targets {
+        if let Target::Universal { architectures, .. } = target {
+            let mut fingerprint = FingerprintBuilder::new("universal");
+            for arch in architectures {
+                fingerprint.file(arch, artifacts.library(arch)?.as_std_path())?;
+            }
+            let output = target.library_path(&artifacts, target_dir, mode)?;
+            run_stage(
+                cache,
+                &format!("universal library for {}", target.display_name()),
+                &fingerprint.finish(),
+                output.as_std_path(),
+                config,
+                || {
+                    run_step(
+                        config,
+                        format!("Creating universal library for {}", target.display_name()),
+                        || target.create_universal_library(&artifacts, target_dir, mode),
+                    )
+                },
+            )?;
+        }
+    }
+
+    Ok(artifacts)
 }
 
 #[allow(clippy::too_many_arguments)]
 fn create_xcframework_with_output(
     targets: &[Target],
-    lib_name: &str,
-    package_name: &str,
+    artifacts: &Artifacts,
+    target_dir: &Utf8Path,
+    generated_dir: &Path,
+    output_dir: &Path,
     xcframework_name: &str,
     ffi_module_name: &str,
-    mode: Mode,
+    mode: &Mode,
     lib_type: LibType,
+    target_settings: &HashMap<&str, TargetSettings>,
     config: &Config,
     privacy_manifest: Option<&Path>,
     bundle_identifier: Option<&str>,
 ) -> Result<()> {
     run_step(config, "Creating XCFramework...", || {
-        // TODO: show command spinner here with xcbuild command
-        let output_dir = PathBuf::from(package_name);
-        // TODO: make this configurable
-        let generated_dir = PathBuf::from("./generated");
-
         create_xcframework(
             targets,
-            lib_name,
+            artifacts,
+            target_dir,
             xcframework_name,
             ffi_module_name,
-            &generated_dir,
-            &output_dir,
+            generated_dir,
+            output_dir,
             mode,
             lib_type,
+            target_settings,
             privacy_manifest,
             bundle_identifier,
         )
@@ -824,12 +2235,18 @@ fn create_xcframework_with_output(
 #[allow(clippy::too_many_arguments)]
 fn create_package_with_output(
     package_name: &str,
+    output_dir: &Path,
+    generated_sources_dir: &Path,
     xcframework_name: &str,
     disable_warnings: bool,
     platforms: &[PlatformSpec],
     swift_tools_version: &str,
     config: &Config,
     privacy_manifest: Option<&Path>,
+    remote: Option<&RemoteBinaryTarget>,
+    swift_sources: Option<&Path>,
+    swift_resources: Option<&Path>,
+    manifest: &ManifestCode,
 ) -> Result<()> {
     run_step(
         config,
@@ -837,21 +2254,168 @@ fn create_package_with_output(
         || {
             create_swiftpackage(
                 package_name,
+                output_dir,
+                generated_sources_dir,
                 xcframework_name,
                 disable_warnings,
                 platforms,
                 swift_tools_version,
                 privacy_manifest,
+                remote,
+                swift_sources,
+                swift_resources,
+                manifest,
             )
         },
-    )?;
+    )
+}
 
-    let spinner = config.silent.not().then(|| {
-        MainSpinner::with_message(format!(
-            "Successfully created Swift Package in '{package_name}/'!"
-        ))
-    });
-    spinner.finish();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
+    #[test]
+    fn overrides_release_and_profile_together() {
+        let metadata = PackageOptions {
+            profile: Some("mobile".to_owned()),
+            ..Default::default()
+        };
+
+        let options = PackageOptions {
+            release: Some(false),
+            ..Default::default()
+        }
+        .or(metadata.clone());
+        assert_eq!(options.release, Some(false));
+        assert_eq!(options.profile, None);
+
+        let options = PackageOptions::default().or(metadata);
+        assert_eq!(options.release, None);
+        assert_eq!(options.profile.as_deref(), Some("mobile"));
+    }
+
+    #[test]
+    fn keeps_disabled_flags_and_empty_lists_of_command_line() {
+        let metadata = PackageOptions {
+            suppress_warnings: Some(true),
+            skip_toolchains_check: Some(true),
+            reproducible: Some(true),
+            exclude_arch: Some(vec!["x86_64-apple-ios".to_owned()]),
+            ..Default::default()
+        };
+        let options = PackageOptions {
+            suppress_warnings: Some(false),
+            skip_toolchains_check: Some(false),
+            reproducible: Some(false),
+            exclude_arch: Some(vec![]),
+            ..Default::default()
+        }
+        .or(metadata);
+
+        assert_eq!(options.suppress_warnings, Some(false));
+        assert_eq!(options.skip_toolchains_check, Some(false));
+        assert_eq!(options.reproducible, Some(false));
+        assert_eq!(options.exclude_arch, Some(vec![]));
+    }
+
+    fn spec(platform: Platform, min_version: Option<&str>) -> PlatformSpec {
+        PlatformSpec {
+            platform,
+            min_version: min_version.map(|v| v.parse().unwrap()),
+        }
+    }
+
+    fn targets_of(platform: Platform) -> Vec<Target> {
+        platform
+            .into_apple_platforms()
+            .into_iter()
+            .map(|p| p.target())
+            .collect()
+    }
+
+    #[test]
+    fn renders_version_constants_and_strings() {
+        let tools_5_5 = Version::new(5, 5);
+        assert_eq!(
+            spec(Platform::Ios, Some("13")).package_swift(&tools_5_5),
+            ".iOS(.v13)"
+        );
+        assert_eq!(
+            spec(Platform::Macos, Some("10_15")).package_swift(&tools_5_5),
+            ".macOS(.v10_15)"
+        );
+        assert_eq!(
+            spec(Platform::Macos, None).package_swift(&tools_5_5),
+            ".macOS(.v10_15)"
+        );
+        // No constant for minor versions or for versions newer than the swift-tools-version
+        assert_eq!(
+            spec(Platform::Ios, Some("13.4")).package_swift(&tools_5_5),
+            ".iOS(\"13.4\")"
+        );
+        assert_eq!(
+            spec(Platform::Ios, Some("17")).package_swift(&tools_5_5),
+            ".iOS(\"17.0\")"
+        );
+        assert_eq!(
+            spec(Platform::Ios, Some("17")).package_swift(&Version::new(5, 9)),
+            ".iOS(.v17)"
+        );
+    }
+
+    #[test]
+    fn rejects_versions_below_minimum_of_swift_tools_version() {
+        let ios = [spec(Platform::Ios, Some("11"))];
+        let targets = targets_of(Platform::Ios);
+        assert!(resolve_platform_versions(&ios, &targets, &Version::new(5, 5)).is_ok());
+        assert!(resolve_platform_versions(&ios, &targets, &Version::new(5, 9)).is_err());
+
+        let visionos = [spec(Platform::Visionos, None)];
+        let targets = targets_of(Platform::Visionos);
+        assert!(resolve_platform_versions(&visionos, &targets, &Version::new(5, 5)).is_err());
+    }
+
+    #[test]
+    fn rejects_versions_below_minimum_of_rustc() {
+        let macos = [spec(Platform::Macos, Some("10.10"))];
+        let targets = targets_of(Platform::Macos);
+        let error = resolve_platform_versions(&macos, &targets, &Version::new(5, 5))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Use macos@10.12 or later"), "{error}");
+    }
+
+    #[test]
+    fn defaults_to_lowest_version_supported_by_all_architectures() {
+        let platforms = [spec(Platform::Ios, None), spec(Platform::Maccatalyst, None)];
+        let targets = [Platform::Ios, Platform::Maccatalyst]
+            .into_iter()
+            .flat_map(targets_of)
+            .collect::<Vec<_>>();
+        let resolved =
+            resolve_platform_versions(&platforms, &targets, &Version::new(5, 5)).unwrap();
+
+        assert_eq!(resolved[0].min_version, Some(Version::new(13, 0)));
+        assert_eq!(resolved[1].min_version, "13.1".parse().ok());
+        assert_eq!(
+            resolved[1].package_swift(&Version::new(5, 5)),
+            ".macCatalyst(\"13.1\")"
+        );
+    }
+
+    #[test]
+    fn raises_deployment_target_of_architectures_with_higher_minimum() {
+        let settings = resolve_target_settings(
+            &[spec(Platform::Ios, Some("13"))],
+            &BTreeMap::new(),
+            &FeatureOptions::default(),
+        )
+        .unwrap();
+        let deployment_target =
+            |arch: &str| settings[arch].env["IPHONEOS_DEPLOYMENT_TARGET"].as_str();
+
+        assert_eq!(deployment_target("aarch64-apple-ios"), "13.0");
+        assert_eq!(deployment_target("x86_64-apple-ios"), "13.0");
+        assert_eq!(deployment_target("aarch64-apple-ios-sim"), "14.0");
+    }
 }
@@ -1,5 +1,13 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::num::NonZeroUsize;
 use std::ops::Not;
-use std::process::{Command, Stdio};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use indicatif::MultiProgress;
 
@@ -29,10 +37,70 @@ where
     result
 }
 
-pub fn run_step_with_commands<S>(config: &Config, title: S, commands: &mut [Command]) -> Result<()>
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Process groups of the running commands of [`run_step_with_concurrent_commands`]. Each command
+/// runs in its own process group, so that cancelling it also kills the rustc and build script
+/// processes cargo spawned. Terminals only send Ctrl-C to their foreground process group, so it
+/// is forwarded to these groups from a signal handler, which cannot lock a mutex.
+static PROCESS_GROUPS: [AtomicI32; 64] = [const { AtomicI32::new(0) }; 64];
+static FORWARD_SIGNALS: Once = Once::new();
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    for group in &PROCESS_GROUPS {
+        let group = group.load(Ordering::SeqCst);
+        if group > 0 {
+            // SAFETY: killpg is async-signal-safe
+            unsafe { libc::killpg(group, signal) };
+        }
+    }
+    // SAFETY: signal and raise are async-signal-safe. The default action terminates this process.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+fn forward_signals_to_process_groups() {
+    FORWARD_SIGNALS.call_once(|| {
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            // SAFETY: the handler only calls async-signal-safe functions
+            unsafe { libc::signal(signal, forward_signal as *const () as libc::sighandler_t) };
+        }
+    });
+}
+
+/// Commands of [`run_step_with_concurrent_commands`] that are currently running
+#[derive(Default)]
+struct RunningCommands {
+    /// Children by id, with their entry in `PROCESS_GROUPS`. Commands beyond the capacity of
+    /// `PROCESS_GROUPS` do not receive forwarded signals.
+    children: HashMap<u32, (Child, Option<&'static AtomicI32>)>,
+    cancelled: bool,
+}
+
+enum CommandOutcome {
+    Succeeded(Vec<u8>),
+    Failed(Vec<u8>),
+    Cancelled,
+}
+
+/// Runs groups of commands concurrently, with at most `jobs` groups running at the same time.
+///
+/// The commands of a group run in their given order, each with its own spinner below the main
+/// spinner. When a command fails, all other running commands are killed together with the
+/// processes they spawned, and groups that have not been started yet are skipped. On success, returns the stdout of every command, grouped like
+/// `groups`.
+pub fn run_step_with_concurrent_commands<S>(
+    config: &Config,
+    title: S,
+    groups: Vec<Vec<Command>>,
+    jobs: NonZeroUsize,
+) -> Result<Vec<Vec<Vec<u8>>>>
 where
     S: ToString,
 {
+    forward_signals_to_process_groups();
     let multi = config.silent.not().then(MultiProgress::new);
     let spinner = config
         .silent
@@ -41,29 +109,287 @@ where
     multi.add(&spinner);
     spinner.start();
 
-    for command in commands {
-        let step = config
-            .silent
-            .not()
-            .then(|| CommandSpinner::with_command(command));
-        multi.add(&step);
-        step.start();
+    let workers = jobs.get().min(groups.len());
+    let outputs = Mutex::new(vec![Vec::new(); groups.len()]);
+    let queue = Mutex::new(groups.into_iter().enumerate());
+    let running = Mutex::new(RunningCommands::default());
+    let error = Mutex::new(None);
 
-        let output = command
-            .stderr(Stdio::piped())
-            .stdout(Stdio::null())
-            .output()
-            .unwrap_or_else(|_| panic!("Failed to execute command: {}", command.info()));
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    // Take the next group in a separate statement, so the queue is not locked
+                    // while the group runs
+                    let next = queue.lock().unwrap().next();
+                    let Some((index, mut group)) = next else {
+                        return;
+                    };
+
+                    for command in &mut group {
+                        if running.lock().unwrap().cancelled {
+                            return;
+                        }
+
+                        let step = config
+                            .silent
+                            .not()
+                            .then(|| CommandSpinner::with_command(command));
+                        multi.add(&step);
+                        step.start();
+
+                        match run_cancellable(command, &running) {
+                            CommandOutcome::Succeeded(stdout) => {
+                                step.finish();
+                                outputs.lock().unwrap()[index].push(stdout);
+                            }
+                            CommandOutcome::Failed(stderr) => {
+                                step.fail();
+                                *error.lock().unwrap() = Some(stderr);
+                                return;
+                            }
+                            CommandOutcome::Cancelled => {
+                                step.fail();
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
 
-        if !output.status.success() {
-            step.fail();
+    match error.into_inner().unwrap() {
+        Some(stderr) => {
             spinner.fail();
-            return Err(output.stderr.into());
+            Err(stderr.into())
+        }
+        None => {
+            spinner.finish();
+            Ok(outputs.into_inner().unwrap())
+        }
+    }
+}
+
+/// Runs a single command, which is killed when another command fails in the meantime
+fn run_cancellable(command: &mut Command, running: &Mutex<RunningCommands>) -> CommandOutcome {
+    let (id, stdout, stderr) = {
+        let mut running = running.lock().unwrap();
+        if running.cancelled {
+            return CommandOutcome::Cancelled;
+        }
+
+        let mut child = match command
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .process_group(0)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                running.cancel();
+                let message = format!("Failed to execute command {}: {e}", command.info());
+                return CommandOutcome::Failed(message.into_bytes());
+            }
+        };
+        let stdout = child.stdout.take().map(read_in_background);
+        let stderr = child.stderr.take().map(read_in_background);
+        let id = child.id();
+        let group = PROCESS_GROUPS.iter().find(|group| {
+            group
+                .compare_exchange(0, id as i32, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+        running.children.insert(id, (child, group));
+        (id, stdout, stderr)
+    };
+
+    let success = loop {
+        {
+            let mut running = running.lock().unwrap();
+            let status = running
+                .children
+                .get_mut(&id)
+                .map(|(child, _)| child.try_wait());
+            match status {
+                Some(Ok(None)) => {}
+                Some(Ok(Some(status))) => {
+                    running.finish(id);
+                    break status.success();
+                }
+                _ => {
+                    running.finish(id);
+                    break false;
+                }
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    if success {
+        return CommandOutcome::Succeeded(join_output(stdout));
+    }
+
+    {
+        let mut running = running.lock().unwrap();
+        if running.cancelled {
+            return CommandOutcome::Cancelled;
+        }
+        running.cancel();
+    }
+
+    CommandOutcome::Failed(join_output(stderr))
+}
+
+/// Reads a pipe on its own thread, since processes spawned by a command can keep it open after
+/// the command itself exited
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = pipe.read_to_end(&mut output);
+        output
+    })
+}
+
+fn join_output(reader: Option<JoinHandle<Vec<u8>>>) -> Vec<u8> {
+    reader
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default()
+}
+
+impl RunningCommands {
+    /// Kills the process groups of all running commands and prevents new commands from being
+    /// started
+    fn cancel(&mut self) {
+        self.cancelled = true;
+        for id in self.children.keys() {
+            // SAFETY: the command has not been waited for yet, so its id is still its group
+            unsafe { libc::killpg(*id as libc::pid_t, libc::SIGKILL) };
+        }
+    }
+
+    /// Forgets a command that exited
+    fn finish(&mut self, id: u32) {
+        if let Some((_, Some(group))) = self.children.remove(&id) {
+            group.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::Instant;
+
+    use super::*;
+
+    const CONFIG: Config = Config {
+        silent: true,
+        accept_all: true,
+    };
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("cargo-swift-step-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sh(dir: &Path, script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script).current_dir(dir);
+        command
+    }
+
+    fn jobs(jobs: usize) -> NonZeroUsize {
+        NonZeroUsize::new(jobs).unwrap()
+    }
+
+    #[test]
+    fn returns_outputs_grouped_like_commands() {
+        let dir = test_dir("outputs");
+        let groups = vec![
+            vec![sh(&dir, "echo a1"), sh(&dir, "echo a2")],
+            vec![sh(&dir, "echo b1")],
+        ];
+
+        let outputs = run_step_with_concurrent_commands(&CONFIG, "", groups, jobs(2)).unwrap();
+
+        assert_eq!(
+            outputs,
+            [
+                vec![b"a1\n".to_vec(), b"a2\n".to_vec()],
+                vec![b"b1\n".to_vec()]
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn runs_at_most_jobs_groups_at_once() {
+        let dir = test_dir("jobs");
+        let groups = (0..6)
+            .map(|_| vec![sh(&dir, "echo start >> log; sleep 0.2; echo end >> log")])
+            .collect();
+
+        run_step_with_concurrent_commands(&CONFIG, "", groups, jobs(2)).unwrap();
+
+        let log = fs::read_to_string(dir.join("log")).unwrap();
+        let (mut running, mut max_running) = (0, 0);
+        for line in log.lines() {
+            running += if line == "start" { 1 } else { -1 };
+            max_running = max_running.max(running);
         }
+        assert_eq!(log.lines().count(), 12);
+        assert_eq!(max_running, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cancels_other_commands_and_their_processes_on_failure() {
+        let dir = test_dir("cancel");
+        let groups = vec![
+            vec![sh(&dir, "sleep 0.3; echo failed >&2; exit 1")],
+            // The background subshell stands in for a rustc process spawned by cargo
+            vec![sh(
+                &dir,
+                "(sleep 1; touch spawned) & sleep 5; touch finished",
+            )],
+            vec![sh(&dir, "touch started")],
+        ];
+        let start = Instant::now();
+
+        let error = run_step_with_concurrent_commands(&CONFIG, "", groups, jobs(2)).unwrap_err();
 
-        step.finish();
+        assert_eq!(error.to_string(), "failed\n");
+        assert!(start.elapsed() < Duration::from_secs(3));
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!dir.join("spawned").exists());
+        assert!(!dir.join("finished").exists());
+        assert!(!dir.join("started").exists());
+        fs::remove_dir_all(dir).unwrap();
     }
 
-    spinner.finish();
-    Ok(())
+    #[test]
+    fn skips_remaining_commands_of_failed_group() {
+        let dir = test_dir("group");
+        let groups = vec![vec![sh(&dir, "exit 3"), sh(&dir, "touch second")]];
+
+        assert!(run_step_with_concurrent_commands(&CONFIG, "", groups, jobs(1)).is_err());
+        assert!(!dir.join("second").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_commands_that_cannot_be_started() {
+        let groups = vec![vec![Command::new("/nonexistent/cargo")]];
+
+        let error = run_step_with_concurrent_commands(&CONFIG, "", groups, jobs(1)).unwrap_err();
+
+        assert!(error
+            .to_string()
+            .starts_with("Failed to execute command /nonexistent/cargo"));
+    }
 }
@@ -0,0 +1,496 @@
+//! Rewriting of the install name of Mach-O dylibs, equivalent to `install_name_tool -id`.
+//!
+//! Load commands are rewritten in place inside the padding between the Mach-O header and the
+//! first section, so the size and layout of the file never change. Universal files are edited
+//! slice by slice. The linker signs arm64 dylibs ad hoc, and dyld refuses to load them once the
+//! load commands no longer match the page hashes of that signature. Like `install_name_tool`, the
+//! page hashes are therefore recomputed, Xcode replaces the signature when signing the app.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::sha256::Sha256;
+use crate::universal::{read_u32, FAT_MAGIC, MH_MAGIC, MH_MAGIC_64};
+use crate::Result;
+
+const FAT_MAGIC_64: u32 = 0xcafe_babf;
+const MH_DYLIB: u32 = 0x6;
+
+const LC_SEGMENT: u32 = 0x1;
+const LC_ID_DYLIB: u32 = 0xd;
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_CODE_SIGNATURE: u32 = 0x1d;
+
+const CSMAGIC_EMBEDDED_SIGNATURE: u32 = 0xfade_0cc0;
+const CSMAGIC_CODEDIRECTORY: u32 = 0xfade_0c02;
+const CSMAGIC_BLOBWRAPPER: u32 = 0xfade_0b01;
+const CS_HASHTYPE_SHA256: u8 = 2;
+
+/// Sets the `LC_ID_DYLIB` install name of the dylib at `path` to `install_name`
+pub fn set_install_name(path: &Path, install_name: &str) -> Result<()> {
+    let mut data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+
+    for (offset, size) in slices(&data)? {
+        let slice = data
+            .get_mut(offset..offset + size)
+            .ok_or_else(|| anyhow!("Truncated universal binary {path:?}"))?;
+        rewrite_load_commands(slice, install_name)
+            .with_context(|| format!("Failed to set install name of {path:?}"))?;
+    }
+
+    fs::write(path, data).with_context(|| format!("Failed to write {path:?}"))?;
+
+    Ok(())
+}
+
+/// Returns offset and size of every thin Mach-O file in `data`
+fn slices(data: &[u8]) -> anyhow::Result<Vec<(usize, usize)>> {
+    let magic = read_be(data, 0);
+    let record_size = match magic {
+        Some(FAT_MAGIC) => 20,
+        Some(FAT_MAGIC_64) => 32,
+        _ => return Ok(vec![(0, data.len())]),
+    };
+
+    let read = |offset| {
+        read_be(data, offset)
+            .map(|value| value as usize)
+            .ok_or_else(|| anyhow!("Truncated universal binary header"))
+    };
+    let nfat_arch = read(4)?;
+
+    (0..nfat_arch)
+        .map(|i| {
+            let record = 8 + i * record_size;
+            if magic == Some(FAT_MAGIC_64) {
+                // 64-bit records store offset and size as big-endian u64 values
+                let high = |offset| read(offset).map(|value| value << 32);
+                Ok((
+                    high(record + 8)? | read(record + 12)?,
+                    high(record + 16)? | read(record + 20)?,
+                ))
+            } else {
+                Ok((read(record + 8)?, read(record + 12)?))
+            }
+        })
+        .collect()
+}
+
+/// Reads a big-endian `u32`, as used by universal binary headers and code signatures unlike the
+/// rest of the Mach-O file
+fn read_be(data: &[u8], offset: usize) -> Option<u32> {
+    read_u32(data, offset).map(u32::swap_bytes)
+}
+
+/// Replaces `LC_ID_DYLIB` in a thin Mach-O dylib
+fn rewrite_load_commands(data: &mut [u8], install_name: &str) -> anyhow::Result<()> {
+    let (header_size, command_align) = match read_u32(data, 0) {
+        Some(MH_MAGIC) => (28, 4),
+        Some(MH_MAGIC_64) => (32, 8),
+        _ => bail!("Not a Mach-O file"),
+    };
+    let truncated = || anyhow!("Truncated Mach-O header");
+    if read_u32(data, 12).ok_or_else(truncated)? != MH_DYLIB {
+        bail!("Not a dynamic library");
+    }
+    let ncmds = read_u32(data, 16).ok_or_else(truncated)?;
+    let sizeofcmds = read_u32(data, 20).ok_or_else(truncated)? as usize;
+
+    let mut commands = Vec::new();
+    let mut has_id = false;
+    let mut code_signature = None;
+    // Load commands can grow until the first byte of segment or section contents
+    let mut first_content = data.len();
+
+    let mut offset = header_size;
+    for _ in 0..ncmds {
+        let cmd = read_u32(data, offset).ok_or_else(truncated)?;
+        let cmdsize = read_u32(data, offset + 4).ok_or_else(truncated)? as usize;
+        let command = data.get(offset..offset + cmdsize).ok_or_else(truncated)?;
+        if cmdsize < 8 {
+            bail!("Invalid load command size {cmdsize}");
+        }
+
+        match cmd {
+            LC_ID_DYLIB => {
+                has_id = true;
+                // timestamp, current_version and compatibility_version are kept
+                let versions = command.get(12..24).ok_or_else(truncated)?;
+                commands.push(string_command(
+                    LC_ID_DYLIB,
+                    versions,
+                    install_name,
+                    command_align,
+                ));
+                offset += cmdsize;
+                continue;
+            }
+            LC_CODE_SIGNATURE => {
+                let dataoff = read_u32(command, 8).ok_or_else(truncated)? as usize;
+                let datasize = read_u32(command, 12).ok_or_else(truncated)? as usize;
+                code_signature = Some((dataoff, datasize));
+            }
+            LC_SEGMENT => first_content = first_content.min(segment_content(command, false)?),
+            LC_SEGMENT_64 => first_content = first_content.min(segment_content(command, true)?),
+            _ => {}
+        }
+        commands.push(command.to_vec());
+        offset += cmdsize;
+    }
+
+    if !has_id {
+        bail!("Dynamic library has no LC_ID_DYLIB load command");
+    }
+
+    let new_size: usize = commands.iter().map(Vec::len).sum();
+    if header_size + new_size > first_content {
+        bail!(
+            "Not enough space in the Mach-O header for the new load commands, \
+            link with `-C link-arg=-Wl,-headerpad_max_install_names` to reserve more space"
+        );
+    }
+
+    let end = header_size + new_size.max(sizeofcmds);
+    let load_commands = &mut data[header_size..end];
+    load_commands.fill(0);
+    let mut offset = 0;
+    for command in &commands {
+        load_commands[offset..offset + command.len()].copy_from_slice(command);
+        offset += command.len();
+    }
+
+    data[16..20].copy_from_slice(&(commands.len() as u32).to_le_bytes());
+    data[20..24].copy_from_slice(&(new_size as u32).to_le_bytes());
+
+    if let Some((offset, size)) = code_signature {
+        update_code_signature(data, offset, size)?;
+    }
+
+    Ok(())
+}
+
+/// Recomputes the page hashes of every code directory in the ad-hoc signature at `offset`, after
+/// the load commands were changed. Signatures made with a certificate would become invalid and
+/// are rejected.
+fn update_code_signature(data: &mut [u8], offset: usize, size: usize) -> anyhow::Result<()> {
+    let truncated = || anyhow!("Truncated code signature");
+    let read_be = |data: &[u8], offset| read_be(data, offset).ok_or_else(truncated);
+
+    let signature = data.get(offset..offset + size).ok_or_else(truncated)?;
+    if read_be(signature, 0)? != CSMAGIC_EMBEDDED_SIGNATURE {
+        bail!("Unknown code signature format");
+    }
+    let mut code_directories = Vec::new();
+    for i in 0..read_be(signature, 8)? as usize {
+        let blob = read_be(signature, 12 + i * 8 + 4)? as usize;
+        match read_be(signature, blob)? {
+            CSMAGIC_CODEDIRECTORY => code_directories.push(offset + blob),
+            // Ad-hoc signatures made by codesign contain an empty CMS blob
+            CSMAGIC_BLOBWRAPPER if read_be(signature, blob + 4)? > 8 => {
+                bail!("The library is signed with a certificate, sign it after packaging instead")
+            }
+            _ => {}
+        }
+    }
+
+    for directory in code_directories {
+        let field = |offset| read_be(data, directory + offset).map(|value| value as usize);
+        let hash_offset = directory + field(16)?;
+        let code_slots = field(28)?;
+        let code_limit = field(32)?;
+        let [hash_size, hash_type, _, page_shift] = read_be(data, directory + 36)?.to_be_bytes();
+        if hash_type != CS_HASHTYPE_SHA256 || hash_size != 32 {
+            bail!("Unsupported hash type {hash_type} in code signature");
+        }
+        // A page size of 0 means that the code is hashed as a single page
+        let page_size = match page_shift {
+            0 => code_limit,
+            shift => 1 << shift,
+        };
+
+        for slot in 0..code_slots {
+            let start = slot * page_size;
+            let end = (start + page_size).min(code_limit);
+            let mut hasher = Sha256::default();
+            hasher.update(data.get(start..end).ok_or_else(truncated)?);
+            let hash = hasher.finish();
+
+            let slot_offset = hash_offset + slot * 32;
+            data.get_mut(slot_offset..slot_offset + 32)
+                .ok_or_else(truncated)?
+                .copy_from_slice(&hash);
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds a load command whose `fields` are followed by a single string, like `dylib_command`
+/// or `rpath_command`
+fn string_command(cmd: u32, fields: &[u8], string: &str, align: usize) -> Vec<u8> {
+    let string_offset = 12 + fields.len();
+    let cmdsize = (string_offset + string.len() + 1).next_multiple_of(align);
+
+    let mut command = Vec::with_capacity(cmdsize);
+    command.extend(cmd.to_le_bytes());
+    command.extend((cmdsize as u32).to_le_bytes());
+    command.extend((string_offset as u32).to_le_bytes());
+    command.extend(fields);
+    command.extend(string.as_bytes());
+    command.resize(cmdsize, 0);
+    command
+}
+
+/// Returns the file offset of the first contents of a segment, or `usize::MAX` if it has none
+fn segment_content(command: &[u8], is_64: bool) -> anyhow::Result<usize> {
+    let truncated = || anyhow!("Truncated segment load command");
+    let read = |offset| read_u32(command, offset).ok_or_else(truncated);
+
+    let (fileoff, filesize, nsects) = if is_64 {
+        (read(40)?, read(48)?, read(64)?)
+    } else {
+        (read(32)?, read(36)?, read(48)?)
+    };
+    // Start of the section headers, size of a section header and position of its offset field
+    let (sections, section_size, offset_field) = if is_64 { (72, 80, 48) } else { (56, 68, 40) };
+
+    // The segment containing the header itself starts at 0, its sections come after the header
+    let mut first = if fileoff != 0 && filesize != 0 {
+        fileoff as usize
+    } else {
+        usize::MAX
+    };
+    for i in 0..nsects as usize {
+        let section = sections + i * section_size;
+        let offset = read(section + offset_field)? as usize;
+        if offset != 0 {
+            first = first.min(offset);
+        }
+    }
+
+    Ok(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPU_TYPE_ARM64: u32 = 0x0100_000c;
+    const LC_RPATH: u32 = 0x8000_001c;
+    /// Offset of the section contents and size of the fixture up to its code signature
+    const CONTENT_OFFSET: usize = 0x200;
+    const CODE_LIMIT: usize = 0x1800;
+
+    /// Builds a minimal arm64 dylib with a `__TEXT` segment, the install name `@rpath/old.dylib`,
+    /// the given rpaths and, optionally, the given code signature
+    fn dylib(rpaths: &[&str], signature: Option<Vec<u8>>) -> Vec<u8> {
+        let mut segment = Vec::new();
+        segment.extend(LC_SEGMENT_64.to_le_bytes());
+        segment.extend((72u32 + 80).to_le_bytes());
+        segment.extend(*b"__TEXT\0\0\0\0\0\0\0\0\0\0");
+        segment.extend(0u64.to_le_bytes()); // vmaddr
+        segment.extend((CODE_LIMIT as u64).to_le_bytes()); // vmsize
+        segment.extend(0u64.to_le_bytes()); // fileoff
+        segment.extend((CODE_LIMIT as u64).to_le_bytes()); // filesize
+        segment.extend([5u32, 5, 1, 0].iter().flat_map(|v| v.to_le_bytes()));
+        segment.extend(*b"__text\0\0\0\0\0\0\0\0\0\0");
+        segment.extend(*b"__TEXT\0\0\0\0\0\0\0\0\0\0");
+        segment.extend((CONTENT_OFFSET as u64).to_le_bytes()); // addr
+        segment.extend(((CODE_LIMIT - CONTENT_OFFSET) as u64).to_le_bytes()); // size
+        segment.extend((CONTENT_OFFSET as u32).to_le_bytes()); // offset
+        segment.extend([0; 28]);
+
+        let versions = [[1, 0, 0, 0], [0, 0, 1, 0], [0, 0, 1, 0]].concat();
+        let mut commands = vec![
+            segment,
+            string_command(LC_ID_DYLIB, &versions, "@rpath/old.dylib", 8),
+        ];
+        commands.extend(
+            rpaths
+                .iter()
+                .map(|rpath| string_command(LC_RPATH, &[], rpath, 8)),
+        );
+        if let Some(signature) = &signature {
+            let mut command = Vec::new();
+            command.extend(LC_CODE_SIGNATURE.to_le_bytes());
+            command.extend(16u32.to_le_bytes());
+            command.extend((CODE_LIMIT as u32).to_le_bytes());
+            command.extend((signature.len() as u32).to_le_bytes());
+            commands.push(command);
+        }
+
+        let mut data = Vec::new();
+        data.extend(MH_MAGIC_64.to_le_bytes());
+        data.extend(CPU_TYPE_ARM64.to_le_bytes());
+        data.extend(0u32.to_le_bytes());
+        data.extend(MH_DYLIB.to_le_bytes());
+        data.extend((commands.len() as u32).to_le_bytes());
+        data.extend((commands.iter().map(Vec::len).sum::<usize>() as u32).to_le_bytes());
+        data.extend([0; 8]);
+        data.extend(commands.concat());
+        data.resize(CONTENT_OFFSET, 0);
+        data.extend((CONTENT_OFFSET..CODE_LIMIT).map(|i| (i % 251) as u8));
+        data.extend(signature.unwrap_or_default());
+        data
+    }
+
+    /// Builds an embedded signature with a SHA-256 code directory of 4 KiB pages whose hashes are
+    /// all zero, and optionally a CMS blob of the given size
+    fn signature(cms_size: Option<u32>) -> Vec<u8> {
+        let code_slots = CODE_LIMIT.div_ceil(4096) as u32;
+        let mut directory = Vec::new();
+        for value in [
+            CSMAGIC_CODEDIRECTORY,
+            48 + code_slots * 32,
+            0x20001,
+            0x2,
+            48,
+            44,
+            0,
+        ] {
+            directory.extend(value.to_be_bytes());
+        }
+        directory.extend(code_slots.to_be_bytes());
+        directory.extend((CODE_LIMIT as u32).to_be_bytes());
+        directory.extend([32, CS_HASHTYPE_SHA256, 0, 12]);
+        directory.extend([0; 4]);
+        directory.extend(*b"lib\0");
+        directory.resize(directory.len() + code_slots as usize * 32, 0);
+
+        let mut blobs = vec![(0u32, directory)];
+        if let Some(cms_size) = cms_size {
+            let mut cms = Vec::new();
+            cms.extend(CSMAGIC_BLOBWRAPPER.to_be_bytes());
+            cms.extend(cms_size.to_be_bytes());
+            cms.resize(cms_size as usize, 0);
+            blobs.push((0x10000, cms));
+        }
+
+        let mut offset = 12 + 8 * blobs.len();
+        let mut index = Vec::new();
+        for (slot, blob) in &blobs {
+            index.extend(slot.to_be_bytes());
+            index.extend((offset as u32).to_be_bytes());
+            offset += blob.len();
+        }
+        let mut signature = Vec::new();
+        signature.extend(CSMAGIC_EMBEDDED_SIGNATURE.to_be_bytes());
+        signature.extend((offset as u32).to_be_bytes());
+        signature.extend((blobs.len() as u32).to_be_bytes());
+        signature.extend(index);
+        for (_, blob) in blobs {
+            signature.extend(blob);
+        }
+        signature
+    }
+
+    /// Reads the string referenced by the `lc_str` offset at `field` of a load command
+    fn load_command_string(command: &[u8], field: usize) -> anyhow::Result<&str> {
+        let start =
+            read_u32(command, field).ok_or_else(|| anyhow!("Truncated load command"))? as usize;
+        let bytes = command
+            .get(start..)
+            .ok_or_else(|| anyhow!("Invalid string offset in load command"))?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+
+        std::str::from_utf8(&bytes[..end]).context("Invalid string in load command")
+    }
+
+    /// Returns the type and contents of every load command
+    fn load_commands(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut offset = 32;
+        (0..read_u32(data, 16).unwrap())
+            .map(|_| {
+                let cmd = read_u32(data, offset).unwrap();
+                let size = read_u32(data, offset + 4).unwrap() as usize;
+                let command = &data[offset..offset + size];
+                offset += size;
+                (cmd, command)
+            })
+            .collect()
+    }
+
+    fn strings(data: &[u8], cmd: u32) -> Vec<&str> {
+        load_commands(data)
+            .into_iter()
+            .filter(|(c, _)| *c == cmd)
+            .map(|(_, command)| load_command_string(command, 8).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn replaces_install_name_and_keeps_other_load_commands() {
+        let mut data = dylib(&["@loader_path", "@executable_path/Frameworks"], None);
+        let original = data.clone();
+
+        rewrite_load_commands(&mut data, "@rpath/MyLib.framework/MyLib").unwrap();
+
+        assert_eq!(
+            strings(&data, LC_ID_DYLIB),
+            ["@rpath/MyLib.framework/MyLib"]
+        );
+        assert_eq!(
+            strings(&data, LC_RPATH),
+            ["@loader_path", "@executable_path/Frameworks"]
+        );
+        let id = load_commands(&data)
+            .into_iter()
+            .find(|(cmd, _)| *cmd == LC_ID_DYLIB)
+            .unwrap()
+            .1;
+        assert_eq!(
+            id[12..24],
+            [[1, 0, 0, 0], [0, 0, 1, 0], [0, 0, 1, 0]].concat()
+        );
+        let sizeofcmds = load_commands(&data)
+            .iter()
+            .map(|(_, command)| command.len() as u32)
+            .sum::<u32>();
+        assert_eq!(read_u32(&data, 20), Some(sizeofcmds));
+        // Nothing after the load commands moved
+        assert_eq!(data.len(), original.len());
+        assert_eq!(data[CONTENT_OFFSET..], original[CONTENT_OFFSET..]);
+    }
+
+    #[test]
+    fn fails_without_space_for_load_commands() {
+        let mut data = dylib(&[], None);
+        let long_path = format!("@rpath/{}", "a".repeat(CONTENT_OFFSET));
+
+        let error = rewrite_load_commands(&mut data, &long_path).unwrap_err();
+
+        assert!(error.to_string().contains("headerpad_max_install_names"));
+    }
+
+    #[test]
+    fn updates_page_hashes_of_code_signature() {
+        let mut data = dylib(&[], Some(signature(None)));
+
+        rewrite_load_commands(&mut data, "@rpath/MyLib.framework/MyLib").unwrap();
+
+        let hashes = &data[CODE_LIMIT + 20 + 48..];
+        for (slot, page) in data[..CODE_LIMIT].chunks(4096).enumerate() {
+            let mut hasher = Sha256::default();
+            hasher.update(page);
+            assert_eq!(hashes[slot * 32..slot * 32 + 32], hasher.finish());
+        }
+    }
+
+    #[test]
+    fn accepts_empty_cms_blob_of_ad_hoc_signature() {
+        let mut data = dylib(&[], Some(signature(Some(8))));
+
+        rewrite_load_commands(&mut data, "@rpath/MyLib.framework/MyLib").unwrap();
+    }
+
+    #[test]
+    fn rejects_signature_with_certificate() {
+        let mut data = dylib(&[], Some(signature(Some(64))));
+
+        let error = rewrite_load_commands(&mut data, "@rpath/MyLib.framework/MyLib").unwrap_err();
+
+        assert!(error.to_string().contains("signed with a certificate"));
+    }
+}
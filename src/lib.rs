@@ -23,13 +23,21 @@ pub(crate) mod console {
 }
 
 mod bindings;
+mod cache;
+mod install_name;
 mod lib_type;
 mod metadata;
 mod path;
+mod reproducible;
+mod rustflags;
+mod sha256;
 mod swiftpackage;
 mod targets;
 mod templating;
+mod universal;
+mod version;
 mod xcframework;
+mod zip;
 
 pub use crate::console::error::Result;
 pub use crate::console::Config;
@@ -37,8 +45,9 @@ pub use commands::*;
 pub use lib_type::LibType;
 pub use targets::*;
 
-use std::fs::{create_dir, remove_dir_all};
+use std::fs::{copy, create_dir, create_dir_all, read_dir, read_link, remove_dir_all, DirEntry};
 use std::io;
+use std::os::unix::fs::symlink;
 use std::path::Path;
 
 fn recreate_dir<P>(dir: P) -> crate::Result<()>
@@ -47,6 +56,51 @@ where
 {
     match remove_dir_all(&dir) {
         Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
-        _ => create_dir(&dir).map_err(|e| e.into()),
+        _ => create_dir_all(&dir).map_err(|e| e.into()),
     }
 }
+
+fn remove_dir_if_exists<P>(dir: P) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    match remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Copies the directory `src` with all its contents to `dst`. Symlinks are copied as symlinks,
+/// so that versioned framework bundles keep their layout.
+fn copy_dir<P, Q>(src: P, dst: Q) -> crate::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    create_dir(&dst)?;
+    for entry in sorted_dir_entries(src)? {
+        let file_type = entry.file_type()?;
+        let target = dst.as_ref().join(entry.file_name());
+        if file_type.is_symlink() {
+            symlink(read_link(entry.path())?, target)?;
+        } else if file_type.is_dir() {
+            copy_dir(entry.path(), target)?;
+        } else {
+            copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the entries of `dir` sorted by file name, so that walks over generated files do not
+/// depend on the order in which the file system returns them
+fn sorted_dir_entries<P>(dir: P) -> io::Result<Vec<DirEntry>>
+where
+    P: AsRef<Path>,
+{
+    let mut entries = read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    Ok(entries)
+}
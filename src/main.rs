@@ -1,10 +1,11 @@
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::process::ExitCode;
 
 use cargo_swift::{
     init,
-    package::{self, FeatureOptions},
-    Config, LibType, Mode,
+    package::{self, CargoOptions, CrateSelection, FeatureOptions, PackageOptions},
+    Config, LibType,
 };
 use clap::{Parser, Subcommand};
 
@@ -44,6 +45,7 @@ impl From<Args> for Config {
 }
 
 #[derive(Subcommand, Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 enum Action {
     #[command()]
     /// Initialize a new Rust project that can be packaged as Swift package
@@ -78,9 +80,13 @@ enum Action {
     #[command()]
     /// Package Rust crate in current directory as Swift package
     ///
+    /// Options that are not given as arguments are read from `[package.metadata.swiftpackage]`
+    /// in the crate's Cargo.toml or from `[workspace.metadata.swiftpackage]` in the workspace's Cargo.toml
     Package {
-        #[arg(short, long, trailing_var_arg = true, num_args = 1..=4, ignore_case = true, value_name = "PLATFORM[@MIN_VERSION]", value_parser = package::PlatformSpecParser)]
-        /// Platforms with optional minimum supported versions. Versions default to 2019, e. g. macos@10_15 or ios@13
+        #[arg(short, long, num_args = 1..=4, ignore_case = true, value_name = "PLATFORM[@MIN_VERSION]", value_parser = package::PlatformSpecParser)]
+        /// Platforms with optional minimum supported versions, e. g. macos@10.15 or ios@15. Versions default to
+        /// macos@10.15, ios@13, tvos@13, watchos@6, visionos@1 and maccatalyst@13.1. Rust targets that require a
+        /// newer version (e. g. aarch64-apple-darwin requires macOS 11) are built for that version instead
         platforms: Option<Vec<package::PlatformSpec>>,
 
         #[arg(long)]
@@ -95,33 +101,43 @@ enum Action {
         /// The xcframework name is now derived from the FFI module name in uniffi.toml.
         xcframework_name: Option<String>,
 
-        #[arg(short, long)]
-        /// Build package optimized for release (default: debug)
-        release: bool,
+        #[arg(short, long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+        /// Build package optimized for release (default: debug). Pass `--release=false` to
+        /// override `release = true` from Cargo.toml.
+        release: Option<bool>,
+
+        #[arg(long, value_name = "NAME", conflicts_with = "release")]
+        /// Build package with the given cargo profile, e.g. a custom `[profile.mobile]`
+        profile: Option<String>,
 
-        #[arg(long, ignore_case = true, default_value_t = package::LibTypeArg::Automatic)]
+        #[arg(long, ignore_case = true)]
         /// Chose the how the library should be build. By default, this will be derived from the lib type provided in Cargo.toml
-        lib_type: package::LibTypeArg,
+        lib_type: Option<package::LibTypeArg>,
 
-        #[arg(long)]
+        #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
         /// Disable warnings in generated Swift package code
-        suppress_warnings: bool,
+        suppress_warnings: Option<bool>,
 
-        #[arg(long)]
+        #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
         /// Disable toolchains check
-        skip_toolchains_check: bool,
+        skip_toolchains_check: Option<bool>,
 
-        #[arg(short = 'F', long, trailing_var_arg = true)]
+        #[arg(short = 'F', long, num_args = 1..)]
         features: Option<Vec<String>>,
 
-        #[arg(long)]
-        all_features: bool,
+        #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+        /// Activate all available features. Pass `--all-features=false` to override
+        /// `all-features = true` from Cargo.toml.
+        all_features: Option<bool>,
 
-        #[arg(long)]
-        no_default_features: bool,
+        #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+        /// Do not activate the `default` feature. Pass `--no-default-features=false` to override
+        /// `no-default-features = true` from Cargo.toml.
+        no_default_features: Option<bool>,
 
-        #[arg(long, default_value = "5.5")]
-        swift_tools_version: String,
+        #[arg(long)]
+        /// Swift tools version declared in Package.swift (default: 5.5)
+        swift_tools_version: Option<String>,
 
         #[arg(long, value_name = "PATH")]
         /// Optional path to a PrivacyInfo.xcprivacy manifest. When provided, the
@@ -130,20 +146,114 @@ enum Action {
         /// package is App Store–compliant out of the box.
         privacy_manifest: Option<PathBuf>,
 
+        #[arg(short, long, value_name = "DIR")]
+        /// Directory to create the Swift package directory in (default: current directory)
+        output_dir: Option<PathBuf>,
+
+        #[arg(long, value_name = "DIR")]
+        /// Directory of hand-written Swift sources (e.g. extensions of the generated types) to
+        /// merge into the generated Swift module
+        swift_sources: Option<PathBuf>,
+
+        #[arg(long, value_name = "DIR")]
+        /// Directory of resources to bundle with the generated Swift module. They are copied to
+        /// `Sources/<package>/Resources` and processed by SwiftPM.
+        swift_resources: Option<PathBuf>,
+
+        #[arg(long, ignore_case = true)]
+        /// Whether the XCFramework is part of the package (local) or zipped for upload and
+        /// downloaded by SwiftPM from `--url-template` (remote). Defaults to local.
+        distribution: Option<package::Distribution>,
+
+        #[arg(long, value_name = "URL")]
+        /// URL the zipped XCFramework will be uploaded to for remote distribution. `{name}` is
+        /// replaced with the XCFramework name and `{version}` with the crate version, e.g.
+        /// `https://github.com/me/mylib/releases/download/{version}/{name}.xcframework.zip`
+        url_template: Option<String>,
+
         #[arg(long, value_name = "ID")]
         /// Bundle identifier for .framework bundles when building dynamic
         /// libraries (e.g. com.example.MyLib). If omitted, you will be
         /// prompted interactively (or com.cargo-swift.{name} is used with -y).
         bundle_identifier: Option<String>,
 
-        #[arg(long = "exclude-arch", value_name = "TRIPLE")]
+        #[arg(long = "exclude-arch", value_name = "TRIPLE", num_args = 0..=1)]
         /// Rust target triple(s) to exclude from the build, mirroring Xcode's
         /// EXCLUDED_ARCHS. Use to drop architectures from universal slices
         /// (e.g. `--exclude-arch x86_64-apple-tvos` to skip Intel tvOS
         /// simulator, which is permanently tier-3 and forces `-Z build-std`).
         /// Universal slices with one remaining arch collapse to a single-arch
-        /// slice; slices with no remaining archs drop out entirely.
-        exclude_arch: Vec<String>,
+        /// slice; slices with no remaining archs drop out entirely. Pass `--exclude-arch` without
+        /// a triple to override `exclude-arch` from Cargo.toml and build all architectures.
+        exclude_arch: Option<Vec<String>>,
+
+        #[arg(short, long, value_name = "N")]
+        /// Maximum number of targets to build in parallel (default: 1). Slices built in parallel
+        /// use separate target dirs in `target/cargo-swift/build/`, so all build dependencies are
+        /// compiled and stored once per slice.
+        jobs: Option<NonZeroUsize>,
+
+        #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+        /// Keep the package replaced by this run as `<package>.bak` next to the new package
+        keep_backup: Option<bool>,
+
+        #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+        /// Build byte-identical packages from the same sources: remap absolute paths embedded by
+        /// rustc and set all timestamps to SOURCE_DATE_EPOCH (default: 1980-01-01)
+        reproducible: Option<bool>,
+
+        #[arg(long)]
+        /// After packaging reproducibly, rebuild the package from scratch in a separate target
+        /// dir and check that it is identical
+        verify_reproducible: bool,
+
+        #[arg(long, value_name = "NAME")]
+        /// Read package options from `[package.metadata.swiftpackage.profiles.NAME]` (or the
+        /// corresponding workspace table). Options from the profile take precedence over options
+        /// outside of it, but are still overridden by command-line arguments.
+        profile_set: Option<String>,
+
+        #[arg(long)]
+        /// Save the resolved package options (including answers to interactive prompts) to
+        /// `[package.metadata.swiftpackage]` in the crate's Cargo.toml
+        save: bool,
+
+        #[arg(long, conflicts_with = "save")]
+        /// Print the resolved packaging plan and every command that would be run, without
+        /// building or writing anything
+        dry_run: bool,
+
+        #[arg(long)]
+        /// Package every crate in the workspace whose library depends on uniffi
+        /// instead of only the crate in the current directory
+        workspace: bool,
+
+        #[arg(long = "package", value_name = "SPEC", conflicts_with = "workspace")]
+        /// Package only the workspace member matching SPEC (`name` or `name@version`)
+        ///
+        /// Unlike cargo's `-p/--package`, there is no short flag, as `-p` selects the platforms.
+        crate_spec: Option<String>,
+
+        #[arg(long, value_name = "PATH")]
+        /// Path to the Cargo.toml of the crate to package (or of the workspace when used with
+        /// --workspace or --package)
+        manifest_path: Option<PathBuf>,
+
+        #[arg(long)]
+        /// Run cargo without accessing the network
+        offline: bool,
+
+        #[arg(long)]
+        /// Require Cargo.lock to be up to date
+        locked: bool,
+
+        #[arg(long)]
+        /// Require Cargo.lock and cache to be up to date
+        frozen: bool,
+
+        #[arg(last = true, value_name = "CARGO_ARGS")]
+        /// Additional arguments passed to every `cargo build` invocation
+        cargo_args: Vec<String>,
     },
 }
 
@@ -168,6 +278,7 @@ fn main() -> ExitCode {
             xcframework_name,
             suppress_warnings,
             release,
+            profile,
             lib_type,
             skip_toolchains_check,
             features,
@@ -175,27 +286,79 @@ fn main() -> ExitCode {
             no_default_features,
             swift_tools_version,
             privacy_manifest,
+            output_dir,
+            swift_sources,
+            swift_resources,
+            distribution,
+            url_template,
             bundle_identifier,
             exclude_arch,
+            jobs,
+            keep_backup,
+            reproducible,
+            verify_reproducible,
+            profile_set,
+            save,
+            dry_run,
+            workspace,
+            crate_spec,
+            manifest_path,
+            offline,
+            locked,
+            frozen,
+            cargo_args,
         } => package::run(
-            platforms,
-            target.as_deref(),
-            package_name,
-            xcframework_name,
-            suppress_warnings,
-            config,
-            if release { Mode::Release } else { Mode::Debug },
-            lib_type,
-            FeatureOptions {
-                features,
-                all_features,
-                no_default_features,
+            PackageOptions {
+                platforms,
+                target,
+                package_name,
+                xcframework_name,
+                suppress_warnings,
+                release,
+                profile,
+                lib_type,
+                skip_toolchains_check,
+                features: FeatureOptions {
+                    features,
+                    all_features,
+                    no_default_features,
+                },
+                swift_tools_version,
+                privacy_manifest,
+                output_dir,
+                swift_sources,
+                swift_resources,
+                distribution,
+                url_template,
+                bundle_identifier,
+                exclude_arch,
+                jobs,
+                keep_backup,
+                // Verifying builds reproducibly, even if `reproducible` is disabled
+                reproducible: if verify_reproducible {
+                    Some(true)
+                } else {
+                    reproducible
+                },
+                target_settings: Default::default(),
+                manifest: Default::default(),
+                profile_set,
             },
-            skip_toolchains_check,
-            &swift_tools_version,
-            privacy_manifest,
-            bundle_identifier,
-            exclude_arch,
+            CrateSelection {
+                manifest_path,
+                package: crate_spec,
+                workspace,
+            },
+            CargoOptions {
+                offline,
+                locked,
+                frozen,
+                build_args: cargo_args,
+            },
+            save,
+            dry_run,
+            verify_reproducible,
+            config,
         ),
     };
 
@@ -1,28 +1,50 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
 
 use camino::Utf8Path;
-use cargo_metadata::{Metadata, MetadataCommand, Package};
+use cargo_metadata::{DependencyKind, Metadata, MetadataCommand, Package, Target, TargetKind};
 use itertools::Itertools;
-use lazy_static::lazy_static;
+use serde::Deserialize;
+use toml_edit::{Array, DocumentMut, Item, Table, TableLike, Value};
 
+use crate::package::{CargoOptions, PackageOptions};
 use crate::path::PathExt;
+use crate::Result;
 
-pub(crate) fn metadata() -> &'static Metadata {
-    lazy_static! {
-        static ref METADATA: Metadata = MetadataCommand::new()
-            .no_deps()
-            .other_options(["--offline".to_string()])
-            .exec()
-            // TODO: Error handling
-            .unwrap();
+/// Name of the table under `[package.metadata]` and `[workspace.metadata]` that holds cargo-swift options
+const METADATA_KEY: &str = "swiftpackage";
+
+/// Runs `cargo metadata` for the workspace of the given Cargo.toml, or for the workspace
+/// of the current directory if no manifest path is given.
+pub(crate) fn load_metadata(
+    manifest_path: Option<&Path>,
+    cargo_options: &CargoOptions,
+) -> Result<Metadata> {
+    let mut command = MetadataCommand::new();
+    if let Some(manifest_path) = manifest_path {
+        command.manifest_path(manifest_path);
     }
 
-    &METADATA
+    command
+        .no_deps()
+        .other_options(cargo_options.args())
+        .exec()
+        .map_err(|e| format!("Could not load cargo metadata: \n {e}").into())
 }
 
 pub(crate) trait MetadataExt {
     fn target_dir(&self) -> Cow<'_, Utf8Path>;
-    fn current_crate(&self) -> Option<&Package>;
+    fn current_crate(&self) -> Result<Option<&Package>>;
+    fn find_crate(&self, spec: &str) -> Result<&Package>;
+    fn crate_at(&self, manifest_path: &Path) -> Result<&Package>;
+    fn uniffi_crates(&self) -> Vec<&Package>;
+    fn swiftpackage_options(
+        &self,
+        package: &Package,
+        profile: Option<&str>,
+    ) -> Result<PackageOptions>;
 }
 
 impl MetadataExt for Metadata {
@@ -37,10 +59,14 @@ impl MetadataExt for Metadata {
     }
 
     /// Returns the package metadata for the crate currently at or above the current working directory.
-    fn current_crate(&self) -> Option<&Package> {
-        let cwd = std::env::current_dir().unwrap();
+    ///
+    /// If crates are nested, the innermost crate containing the current working directory is returned.
+    fn current_crate(&self) -> Result<Option<&Package>> {
+        let cwd = std::env::current_dir()
+            .map_err(|e| format!("Could not read the current directory: \n {e}"))?;
 
-        self.workspace_packages()
+        Ok(self
+            .workspace_packages()
             .into_iter()
             .filter_map(|p| {
                 let parent = p
@@ -54,7 +80,520 @@ impl MetadataExt for Metadata {
                     None
                 }
             })
-            .find_or_first(|(_, parent)| parent.starts_with(&cwd))
-            .map(|(package, _)| package)
+            .max_by_key(|(_, parent)| parent.components().count())
+            .map(|(package, _)| package))
+    }
+
+    /// Returns the package metadata for the workspace member matching the given package
+    /// specification, which is either `name` or `name@version`.
+    fn find_crate(&self, spec: &str) -> Result<&Package> {
+        let (name, version) = match spec.split_once('@') {
+            Some((name, version)) => (name, Some(version)),
+            None => (spec, None),
+        };
+
+        let matches = self
+            .workspace_packages()
+            .into_iter()
+            .filter(|p| *p.name == *name)
+            .filter(|p| version.is_none_or(|v| p.version.to_string() == v))
+            .collect::<Vec<_>>();
+
+        match matches.as_slice() {
+            [] => Err(format!("Package `{spec}` is not a member of the workspace!").into()),
+            [package] => Ok(package),
+            _ => Err(format!(
+                "Package specification `{spec}` is ambiguous, it matches: {}\n  Use `name@version` to select one of them.",
+                matches
+                    .iter()
+                    .map(|p| format!("{}@{}", p.name, p.version))
+                    .join(", ")
+            )
+            .into()),
+        }
+    }
+
+    /// Returns the package metadata for the workspace member with the given Cargo.toml.
+    fn crate_at(&self, manifest_path: &Path) -> Result<&Package> {
+        let manifest_path = manifest_path
+            .canonicalize()
+            .map_err(|e| format!("Could not find {}: \n {e}", manifest_path.display()))?;
+
+        self.workspace_packages()
+            .into_iter()
+            .find(|p| {
+                p.manifest_path
+                    .canonicalize()
+                    .is_ok_and(|p| p == manifest_path)
+            })
+            .ok_or_else(|| {
+                format!(
+                    "{} does not belong to a single crate! Select a crate with --package or package all crates with --workspace.",
+                    manifest_path.display()
+                )
+                .into()
+            })
+    }
+
+    /// Returns the package metadata for all crates in the workspace with a library that depends on uniffi.
+    fn uniffi_crates(&self) -> Vec<&Package> {
+        self.workspace_packages()
+            .into_iter()
+            .filter(|p| library_target(p).is_some())
+            .filter(|p| {
+                p.dependencies
+                    .iter()
+                    .any(|d| d.name == "uniffi" && d.kind == DependencyKind::Normal)
+            })
+            .collect()
+    }
+
+    /// Returns the packaging options from `[package.metadata.swiftpackage]` of the given crate,
+    /// falling back to `[workspace.metadata.swiftpackage]` for options the crate does not set.
+    ///
+    /// If a profile is given, options from the `profiles.<profile>` table take precedence over the
+    /// options outside of it. The profile needs to be declared in the crate or workspace metadata.
+    fn swiftpackage_options(
+        &self,
+        package: &Package,
+        profile: Option<&str>,
+    ) -> Result<PackageOptions> {
+        let crate_dir = package
+            .manifest_path
+            .parent()
+            .expect("The Cargo.toml path should end with /Cargo.toml");
+
+        let crate_metadata = parse_metadata(&package.metadata, "package", &package.manifest_path)?
+            .relative_to(crate_dir.as_std_path());
+        let workspace_metadata = parse_metadata(
+            &self.workspace_metadata,
+            "workspace",
+            &self.workspace_root.join("Cargo.toml"),
+        )?
+        .relative_to(self.workspace_root.as_std_path());
+
+        let Some(profile) = profile else {
+            return Ok(crate_metadata.options.or(workspace_metadata.options));
+        };
+
+        let crate_profile = crate_metadata.profiles.get(profile).cloned();
+        let workspace_profile = workspace_metadata.profiles.get(profile).cloned();
+        if crate_profile.is_none() && workspace_profile.is_none() {
+            return Err(format!(
+                "No profile `{profile}` found in [package.metadata.{METADATA_KEY}.profiles] of {} or [workspace.metadata.{METADATA_KEY}.profiles] of the workspace!",
+                package.manifest_path
+            )
+            .into());
+        }
+
+        Ok(crate_profile
+            .unwrap_or_default()
+            .or(workspace_profile.unwrap_or_default())
+            .or(crate_metadata.options)
+            .or(workspace_metadata.options))
+    }
+}
+
+/// Returns the library target of a crate. Libraries with a `crate-type` other than `lib`, e.g.
+/// only `staticlib`, have the kinds of their crate types instead of `lib`.
+pub(crate) fn library_target(package: &Package) -> Option<&Target> {
+    const LIBRARY_KINDS: [TargetKind; 5] = [
+        TargetKind::Lib,
+        TargetKind::RLib,
+        TargetKind::DyLib,
+        TargetKind::CDyLib,
+        TargetKind::StaticLib,
+    ];
+
+    package
+        .targets
+        .iter()
+        .find(|t| t.kind.iter().any(|kind| LIBRARY_KINDS.contains(kind)))
+}
+
+/// Contents of a `[package.metadata.swiftpackage]` or `[workspace.metadata.swiftpackage]` table
+#[derive(Deserialize, Default)]
+struct SwiftPackageMetadata {
+    #[serde(flatten)]
+    options: PackageOptions,
+    #[serde(default)]
+    profiles: BTreeMap<String, PackageOptions>,
+}
+
+impl SwiftPackageMetadata {
+    fn relative_to(self, dir: &Path) -> Self {
+        Self {
+            options: self.options.relative_to(dir),
+            profiles: self
+                .profiles
+                .into_iter()
+                .map(|(name, options)| (name, options.relative_to(dir)))
+                .collect(),
+        }
+    }
+}
+
+fn parse_metadata(
+    metadata: &serde_json::Value,
+    section: &str,
+    manifest_path: &Utf8Path,
+) -> Result<SwiftPackageMetadata> {
+    match metadata.get(METADATA_KEY) {
+        Some(value) => serde_json::from_value(value.clone()).map_err(|e| {
+            format!("Invalid [{section}.metadata.{METADATA_KEY}] in {manifest_path}: \n {e}").into()
+        }),
+        None => Ok(SwiftPackageMetadata::default()),
+    }
+}
+
+/// Writes the given packaging options to `[package.metadata.swiftpackage]` (or to
+/// `[package.metadata.swiftpackage.profiles.<profile>]` if a profile is given) in the Cargo.toml at
+/// `manifest_path`. Existing keys are overwritten, while all other content, formatting and comments
+/// of the manifest are kept as they are.
+pub(crate) fn save_swiftpackage_options(
+    manifest_path: &Utf8Path,
+    profile: Option<&str>,
+    options: &PackageOptions,
+) -> Result<()> {
+    let crate_dir = manifest_path
+        .parent()
+        .expect("The Cargo.toml path should end with /Cargo.toml");
+    let mut options = options.clone();
+    for path in [
+        &mut options.privacy_manifest,
+        &mut options.output_dir,
+        &mut options.swift_sources,
+        &mut options.swift_resources,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        let absolute = std::path::absolute(&path)?;
+        *path = match absolute.strip_prefix(crate_dir) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => absolute,
+        };
+    }
+
+    let manifest = fs::read_to_string(manifest_path)
+        .map_err(|e| format!("Could not read {manifest_path}: \n {e}"))?;
+    let mut document: DocumentMut = manifest
+        .parse()
+        .map_err(|e| format!("Could not parse {manifest_path}: \n {e}"))?;
+
+    let mut keys = vec!["package", "metadata", METADATA_KEY];
+    if let Some(profile) = profile {
+        keys.extend(["profiles", profile]);
+    }
+
+    let mut table = document.as_table_mut() as &mut dyn TableLike;
+    for (i, key) in keys.iter().enumerate() {
+        let path = keys[..=i].join(".");
+        // Only the innermost table gets its own header, parent tables are declared implicitly
+        let is_target = i == keys.len() - 1;
+
+        table = table
+            .entry(key)
+            .or_insert_with(|| {
+                let mut table = Table::new();
+                table.set_implicit(!is_target);
+                Item::Table(table)
+            })
+            .as_table_like_mut()
+            .ok_or_else(|| format!("[{path}] in {manifest_path} is not a table"))?;
+    }
+
+    let serde_json::Value::Object(values) = serde_json::to_value(&options)
+        .map_err(|e| format!("Could not serialize package options: \n {e}"))?
+    else {
+        unreachable!("Package options should always serialize to a map");
+    };
+    for (key, value) in values {
+        let value = toml_value(value).ok_or_else(|| format!("Could not save option {key}"))?;
+        match table.get_mut(&key).and_then(Item::as_value_mut) {
+            // Keep comments and whitespace around existing values
+            Some(existing) => {
+                let decor = existing.decor().clone();
+                *existing = value;
+                *existing.decor_mut() = decor;
+            }
+            None => {
+                table.insert(&key, Item::Value(value));
+            }
+        }
+    }
+
+    fs::write(manifest_path, document.to_string())
+        .map_err(|e| format!("Could not write {manifest_path}: \n {e}"))?;
+
+    Ok(())
+}
+
+fn toml_value(value: serde_json::Value) -> Option<Value> {
+    match value {
+        serde_json::Value::Bool(b) => Some(b.into()),
+        serde_json::Value::String(s) => Some(s.into()),
+        serde_json::Value::Number(n) => n.as_i64().map(Value::from),
+        serde_json::Value::Array(items) => items
+            .into_iter()
+            .map(toml_value)
+            .collect::<Option<Array>>()
+            .map(Value::Array),
+        serde_json::Value::Null | serde_json::Value::Object(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Writes a workspace with the given root Cargo.toml and members (directory and Cargo.toml)
+    /// and loads its metadata
+    fn fixture_workspace(
+        name: &str,
+        workspace_manifest: &str,
+        members: &[(&str, &str)],
+    ) -> (PathBuf, Metadata) {
+        let dir = std::env::temp_dir().join(format!(
+            "cargo-swift-metadata-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Cargo.toml"), workspace_manifest).unwrap();
+        for (member, manifest) in members {
+            fs::create_dir_all(dir.join(member).join("src")).unwrap();
+            fs::write(dir.join(member).join("Cargo.toml"), manifest).unwrap();
+            fs::write(dir.join(member).join("src/lib.rs"), "").unwrap();
+        }
+
+        let cargo_options = CargoOptions {
+            offline: true,
+            ..Default::default()
+        };
+        let metadata = load_metadata(Some(&dir.join("Cargo.toml")), &cargo_options).unwrap();
+        (dir, metadata)
+    }
+
+    #[test]
+    fn layers_command_line_over_profile_over_crate_over_workspace() {
+        let (dir, metadata) = fixture_workspace(
+            "layers",
+            r#"
+[workspace]
+members = ["app"]
+
+[workspace.metadata.swiftpackage]
+name = "Workspace"
+release = true
+swift-tools-version = "5.9"
+exclude-arch = ["x86_64-apple-ios"]
+jobs = 2
+
+[workspace.metadata.swiftpackage.profiles.ci]
+suppress-warnings = true
+"#,
+            &[(
+                "app",
+                r#"
+[package]
+name = "app"
+version = "0.1.0"
+edition = "2021"
+
+[package.metadata.swiftpackage]
+name = "Crate"
+swift-tools-version = "5.7"
+output-dir = "out"
+keep-backup = true
+
+[package.metadata.swiftpackage.profiles.ci]
+name = "Profile"
+reproducible = true
+"#,
+            )],
+        );
+        let package = metadata.find_crate("app").unwrap();
+        let command_line = PackageOptions {
+            release: Some(false),
+            exclude_arch: Some(vec![]),
+            keep_backup: Some(false),
+            ..Default::default()
+        };
+
+        let options = command_line
+            .clone()
+            .or(metadata.swiftpackage_options(package, Some("ci")).unwrap());
+        assert_eq!(options.package_name.as_deref(), Some("Profile"));
+        assert_eq!(options.reproducible, Some(true));
+        assert_eq!(options.suppress_warnings, Some(true));
+        assert_eq!(options.swift_tools_version.as_deref(), Some("5.7"));
+        assert_eq!(options.output_dir, Some(dir.join("app/out")));
+        assert_eq!(options.jobs, NonZeroUsize::new(2));
+        assert_eq!(options.release, Some(false));
+        assert_eq!(options.exclude_arch, Some(vec![]));
+        assert_eq!(options.keep_backup, Some(false));
+
+        let options =
+            PackageOptions::default().or(metadata.swiftpackage_options(package, None).unwrap());
+        assert_eq!(options.package_name.as_deref(), Some("Crate"));
+        assert_eq!(options.reproducible, None);
+        assert_eq!(options.suppress_warnings, None);
+        assert_eq!(options.release, Some(true));
+        assert_eq!(
+            options.exclude_arch,
+            Some(vec!["x86_64-apple-ios".to_owned()])
+        );
+        assert_eq!(options.keep_backup, Some(true));
+
+        assert!(metadata
+            .swiftpackage_options(package, Some("missing"))
+            .is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn saves_options_keeping_rest_of_manifest() {
+        let dir =
+            std::env::temp_dir().join(format!("cargo-swift-metadata-save-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let manifest_path = camino::Utf8PathBuf::from_path_buf(dir.join("Cargo.toml")).unwrap();
+        let head = r#"# The crate to package
+[package]
+name = "app" # named after the app
+version = "0.1.0"
+
+[package.metadata.swiftpackage]
+# Shown in Xcode
+name = "Old"   # renamed later
+release = true
+"#;
+        let tail = r#"
+[package.metadata.swiftpackage.profiles.ci]
+release = false # faster CI builds
+
+[dependencies]
+# none yet
+"#;
+        fs::write(&manifest_path, format!("{head}{tail}")).unwrap();
+
+        let options = PackageOptions {
+            package_name: Some("New".to_owned()),
+            output_dir: Some(dir.join("out")),
+            swift_sources: Some(PathBuf::from("/elsewhere/swift")),
+            ..Default::default()
+        };
+        save_swiftpackage_options(&manifest_path, None, &options).unwrap();
+
+        let saved = fs::read_to_string(&manifest_path).unwrap();
+        let expected_head = head.replace(r#""Old"   "#, r#""New"   "#);
+        let expected_head =
+            format!("{expected_head}output-dir = \"out\"\nswift-sources = \"/elsewhere/swift\"\n");
+        assert_eq!(saved, format!("{expected_head}{tail}"));
+
+        save_swiftpackage_options(&manifest_path, Some("ci"), &options).unwrap();
+        let saved = fs::read_to_string(&manifest_path).unwrap();
+        assert!(saved.starts_with(&expected_head));
+        assert!(saved.contains(
+            "[package.metadata.swiftpackage.profiles.ci]\nrelease = false # faster CI builds\nname = \"New\"\n"
+        ));
+        assert!(saved.ends_with("\n[dependencies]\n# none yet\n"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finds_crate_by_name_and_version() {
+        let manifest = |name: &str, version: &str| {
+            format!("[package]\nname = \"{name}\"\nversion = \"{version}\"\nedition = \"2021\"\n")
+        };
+        let (dir, mut metadata) = fixture_workspace(
+            "find",
+            "[workspace]\nmembers = [\"app\", \"core\"]\n",
+            &[
+                ("app", &manifest("app", "0.1.0")),
+                ("core", &manifest("core", "0.2.0")),
+            ],
+        );
+
+        assert_eq!(metadata.find_crate("app").unwrap().name.as_str(), "app");
+        assert_eq!(
+            metadata.find_crate("core@0.2.0").unwrap().name.as_str(),
+            "core"
+        );
+        assert!(metadata.find_crate("core@0.1.0").is_err());
+        let error = metadata.find_crate("missing").unwrap_err().to_string();
+        assert!(error.contains("`missing` is not a member"), "{error}");
+
+        // cargo rejects workspaces with two members of the same name, so another version of a
+        // member is added by hand
+        let mut other_version = metadata.find_crate("core").unwrap().clone();
+        other_version.version = "0.3.0".parse().unwrap();
+        other_version.id.repr.push_str("-other");
+        metadata.workspace_members.push(other_version.id.clone());
+        metadata.packages.push(other_version);
+        let error = metadata.find_crate("core").unwrap_err().to_string();
+        assert!(error.contains("core@0.2.0, core@0.3.0"), "{error}");
+        assert_eq!(
+            metadata
+                .find_crate("core@0.3.0")
+                .unwrap()
+                .version
+                .to_string(),
+            "0.3.0"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finds_uniffi_crates_with_any_library_crate_type() {
+        let manifest = |name: &str, crate_types: Option<&str>, dependencies: &str| {
+            let lib = crate_types
+                .map(|crate_types| format!("\n[lib]\ncrate-type = {crate_types}\n"))
+                .unwrap_or_default();
+            format!(
+                "[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n{lib}\n{dependencies}"
+            )
+        };
+        let uniffi = "[dependencies]\nuniffi = \"0.29\"\n";
+        let (dir, metadata) = fixture_workspace(
+            "uniffi",
+            "[workspace]\nmembers = [\"*\"]\n",
+            &[
+                ("lib", &manifest("lib", None, uniffi)),
+                (
+                    "static",
+                    &manifest("static", Some("[\"staticlib\"]"), uniffi),
+                ),
+                (
+                    "dynamic",
+                    &manifest("dynamic", Some("[\"cdylib\"]"), uniffi),
+                ),
+                (
+                    "both",
+                    &manifest("both", Some("[\"staticlib\", \"rlib\"]"), uniffi),
+                ),
+                ("helpers", &manifest("helpers", None, "")),
+                (
+                    "build",
+                    &manifest("build", None, "[build-dependencies]\nuniffi = \"0.29\"\n"),
+                ),
+            ],
+        );
+
+        let names = metadata
+            .uniffi_crates()
+            .into_iter()
+            .map(|p| p.name.as_str())
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["both", "dynamic", "lib", "static"]);
+
+        fs::remove_dir_all(dir).unwrap();
     }
 }
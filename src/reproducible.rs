@@ -0,0 +1,236 @@
+//! Support for packaging with `--reproducible`, where building the same sources again produces
+//! a byte-identical package.
+//!
+//! rustc embeds absolute paths of the sources into debug info and panic messages, so paths of the
+//! workspace, the cargo home and the target dir are remapped to fixed prefixes. Build scripts and
+//! Apple's archive tools are asked to avoid timestamps, and the modification times of all files
+//! in the package are set to `SOURCE_DATE_EPOCH`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use camino::Utf8Path;
+
+use crate::package::TargetSettings;
+use crate::sha256::file_checksum;
+use crate::{sorted_dir_entries, Result};
+
+/// 1980-01-01 00:00 UTC, the timestamp of the entries of zipped XCFrameworks
+const DEFAULT_SOURCE_DATE_EPOCH: u64 = 315532800;
+
+/// Timestamp for all files of the package, read from `SOURCE_DATE_EPOCH` if set
+pub fn source_date_epoch() -> Result<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.trim().parse().map_err(|_| {
+            format!("Invalid SOURCE_DATE_EPOCH `{epoch}`, expected seconds since 1970-01-01").into()
+        }),
+        Err(_) => Ok(DEFAULT_SOURCE_DATE_EPOCH),
+    }
+}
+
+/// Adds the rustflags and environment variables for reproducible builds to the settings of a
+/// target
+pub fn make_reproducible(
+    settings: &mut TargetSettings,
+    workspace_root: &Utf8Path,
+    target_dir: &Utf8Path,
+    source_date_epoch: u64,
+) {
+    // rustc applies the remapping given last first, so more specific paths come after the paths
+    // containing them
+    let mut remaps = vec![format!("{workspace_root}=.")];
+    if let Some(cargo_home) = cargo_home() {
+        remaps.push(format!("{}=/cargo", cargo_home.display()));
+    }
+    // The target dir is usually given relative to the current directory
+    let target_dir = std::path::absolute(target_dir).unwrap_or_else(|_| target_dir.into());
+    remaps.push(format!("{}=/target", target_dir.display()));
+    settings.rustflags.extend(
+        remaps
+            .into_iter()
+            .map(|remap| format!("--remap-path-prefix={remap}")),
+    );
+
+    settings.env.insert(
+        "SOURCE_DATE_EPOCH".to_owned(),
+        source_date_epoch.to_string(),
+    );
+    // Makes ar and libtool write archives without timestamps, e.g. for C code built by build
+    // scripts
+    settings
+        .env
+        .insert("ZERO_AR_DATE".to_owned(), "1".to_owned());
+}
+
+pub(crate) fn cargo_home() -> Option<PathBuf> {
+    std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")))
+}
+
+/// Sets the modification time of `dir` and all files and directories in it to
+/// `source_date_epoch`. Symlinks are skipped, as their own timestamps cannot be set.
+pub fn normalize_mtimes(dir: &Path, source_date_epoch: u64) -> Result<()> {
+    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(source_date_epoch);
+    for entry in sorted_dir_entries(dir).with_context(|| format!("Failed to read {dir:?}"))? {
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            normalize_mtimes(&entry.path(), source_date_epoch)?;
+        } else if file_type.is_file() {
+            set_modified(&entry.path(), time)?;
+        }
+    }
+    // Set last, as creating the contents changed it
+    set_modified(dir, time)
+}
+
+fn set_modified(path: &Path, time: SystemTime) -> Result<()> {
+    File::open(path)
+        .and_then(|file| file.set_modified(time))
+        .with_context(|| format!("Failed to set modification time of {path:?}"))?;
+
+    Ok(())
+}
+
+/// Returns the SHA-256 of every file in `dir` and the target of every symlink, keyed by their
+/// path relative to `dir`
+pub fn checksums(dir: &Path) -> Result<BTreeMap<String, String>> {
+    let mut checksums = BTreeMap::new();
+    collect_checksums(dir, "", &mut checksums)?;
+
+    Ok(checksums)
+}
+
+fn collect_checksums(
+    dir: &Path,
+    prefix: &str,
+    checksums: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    for entry in sorted_dir_entries(dir).with_context(|| format!("Failed to read {dir:?}"))? {
+        let path = entry.path();
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            checksums.insert(name, format!("-> {}", target.display()));
+        } else if file_type.is_dir() {
+            collect_checksums(&path, &format!("{name}/"), checksums)?;
+        } else {
+            let checksum =
+                file_checksum(&path).with_context(|| format!("Failed to read {path:?}"))?;
+            checksums.insert(name, checksum);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the paths of all files that are missing in one of the directories or differ
+pub fn differences(
+    expected: &BTreeMap<String, String>,
+    actual: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut paths = expected
+        .keys()
+        .chain(actual.keys())
+        .filter(|path| expected.get(*path) != actual.get(*path))
+        .cloned()
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "cargo-swift-reproducible-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn sets_mtimes_to_source_date_epoch() {
+        let dir = test_dir("mtimes");
+        write_files(
+            &dir,
+            &[("Package.swift", ""), ("Sources/Lib/Lib.swift", "")],
+        );
+
+        normalize_mtimes(&dir, DEFAULT_SOURCE_DATE_EPOCH).unwrap();
+
+        let expected = SystemTime::UNIX_EPOCH + Duration::from_secs(DEFAULT_SOURCE_DATE_EPOCH);
+        for path in [
+            "",
+            "Package.swift",
+            "Sources",
+            "Sources/Lib",
+            "Sources/Lib/Lib.swift",
+        ] {
+            let modified = fs::metadata(dir.join(path)).unwrap().modified().unwrap();
+            assert_eq!(modified, expected, "{path}");
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checksums_do_not_depend_on_creation_order() {
+        let files = [("b.swift", "b"), ("a/c.swift", "c"), ("a.swift", "a")];
+        let first = test_dir("checksums-first");
+        let second = test_dir("checksums-second");
+        write_files(&first, &files);
+        write_files(&second, &files.iter().rev().copied().collect::<Vec<_>>());
+
+        let checksums_of_first = checksums(&first).unwrap();
+        assert_eq!(
+            checksums_of_first.keys().collect::<Vec<_>>(),
+            ["a.swift", "a/c.swift", "b.swift"]
+        );
+        assert_eq!(checksums_of_first, checksums(&second).unwrap());
+
+        fs::remove_dir_all(first).unwrap();
+        fs::remove_dir_all(second).unwrap();
+    }
+
+    #[test]
+    fn reports_changed_and_missing_files() {
+        let expected = test_dir("differences-expected");
+        let actual = test_dir("differences-actual");
+        write_files(
+            &expected,
+            &[("same", "1"), ("changed", "1"), ("removed", "1")],
+        );
+        write_files(&actual, &[("same", "1"), ("changed", "2"), ("added", "1")]);
+
+        let expected_checksums = checksums(&expected).unwrap();
+        assert_eq!(
+            differences(&expected_checksums, &checksums(&actual).unwrap()),
+            ["added", "changed", "removed"]
+        );
+        assert!(differences(&expected_checksums, &expected_checksums).is_empty());
+
+        fs::remove_dir_all(expected).unwrap();
+        fs::remove_dir_all(actual).unwrap();
+    }
+}
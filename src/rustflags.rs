@@ -0,0 +1,309 @@
+//! Rustflags of target settings on top of the rustflags cargo would use anyway.
+//!
+//! cargo takes rustflags from exactly one source: `CARGO_ENCODED_RUSTFLAGS`, `RUSTFLAGS`,
+//! `target.<triple>.rustflags` (joined with matching `target.<cfg>.rustflags`) or
+//! `build.rustflags`, in this order. Rustflags of target settings are passed as
+//! `target.<triple>.rustflags`, so they would silently replace rustflags from the environment
+//! or from `build.rustflags`. Those are looked up here and passed along with them instead.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use toml_edit::{DocumentMut, Item, Value};
+
+use crate::package::TargetSettings;
+use crate::reproducible::cargo_home;
+use crate::Result;
+
+/// Rustflags from the environment and cargo config files of the current directory
+#[derive(Debug, Default)]
+pub struct CargoConfig {
+    /// Flags of `CARGO_ENCODED_RUSTFLAGS` or `RUSTFLAGS`
+    env_rustflags: Option<Vec<String>>,
+    /// Flags of `build.rustflags`, including `CARGO_BUILD_RUSTFLAGS`
+    build_rustflags: Vec<String>,
+    /// Keys of the `target` tables with rustflags, i.e. target triples or `cfg(...)` expressions
+    target_rustflags: HashSet<String>,
+}
+
+impl CargoConfig {
+    /// Reads rustflags from the environment and from the cargo config files cargo would read
+    /// when run in the current directory
+    pub fn load() -> Result<Self> {
+        let mut config = Self {
+            env_rustflags: env_rustflags(|key| std::env::var(key).ok()),
+            ..Default::default()
+        };
+        for path in config_files()? {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read cargo config {}", path.display()))?;
+            config
+                .add_file(&contents)
+                .with_context(|| format!("Failed to parse cargo config {}", path.display()))?;
+        }
+        for (key, value) in std::env::vars() {
+            if key == "CARGO_BUILD_RUSTFLAGS" {
+                config
+                    .build_rustflags
+                    .extend(value.split_whitespace().map(str::to_owned));
+            } else if let Some(triple) = key
+                .strip_prefix("CARGO_TARGET_")
+                .and_then(|key| key.strip_suffix("_RUSTFLAGS"))
+            {
+                config
+                    .target_rustflags
+                    .insert(triple.to_lowercase().replace('_', "-"));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Merges a config file into this config. Files need to be added in order of increasing
+    /// precedence, as arrays of rustflags are joined and strings are replaced.
+    fn add_file(&mut self, contents: &str) -> anyhow::Result<()> {
+        let document: DocumentMut = contents.parse()?;
+        if let Some(rustflags) = document.get("build").and_then(|b| b.get("rustflags")) {
+            match rustflags_of(rustflags) {
+                Flags::List(flags) => self.build_rustflags.extend(flags),
+                Flags::String(flags) => self.build_rustflags = flags,
+            }
+        }
+        if let Some(targets) = document.get("target").and_then(Item::as_table_like) {
+            for (key, target) in targets.iter() {
+                if target.get("rustflags").is_some() {
+                    self.target_rustflags.insert(key.to_owned());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether cargo ignores `build.rustflags` for `arch`. Target tables for `cfg(...)`
+    /// expressions are assumed to match, as their rustflags would already replace
+    /// `build.rustflags` without cargo-swift.
+    fn has_target_rustflags(&self, arch: &str) -> bool {
+        self.target_rustflags
+            .iter()
+            .any(|key| key == arch || key.starts_with("cfg("))
+    }
+}
+
+enum Flags {
+    List(Vec<String>),
+    String(Vec<String>),
+}
+
+fn rustflags_of(item: &Item) -> Flags {
+    match item.as_value() {
+        Some(Value::Array(array)) => Flags::List(
+            array
+                .iter()
+                .filter_map(|flag| flag.as_str().map(str::to_owned))
+                .collect(),
+        ),
+        Some(Value::String(flags)) => Flags::String(
+            flags
+                .value()
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect(),
+        ),
+        _ => Flags::List(vec![]),
+    }
+}
+
+fn env_rustflags(var: impl Fn(&str) -> Option<String>) -> Option<Vec<String>> {
+    if let Some(encoded) = var("CARGO_ENCODED_RUSTFLAGS") {
+        Some(
+            encoded
+                .split('\x1f')
+                .filter(|flag| !flag.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        )
+    } else {
+        var("RUSTFLAGS").map(|flags| flags.split_whitespace().map(str::to_owned).collect())
+    }
+}
+
+/// Cargo config files in order of increasing precedence: the one in the cargo home, then the
+/// ones in the current directory and its ancestors, from the root down
+fn config_files() -> Result<Vec<PathBuf>> {
+    let current_dir = std::env::current_dir()?;
+    let mut dirs = current_dir
+        .ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .collect::<Vec<_>>();
+    if let Some(cargo_home) = cargo_home() {
+        if !dirs.contains(&cargo_home) {
+            dirs.push(cargo_home);
+        }
+    }
+
+    Ok(dirs
+        .into_iter()
+        .rev()
+        .filter_map(|dir| {
+            ["config.toml", "config"]
+                .into_iter()
+                .map(|name| dir.join(name))
+                .find(|path| path.is_file())
+        })
+        .collect())
+}
+
+/// Adds the rustflags cargo would use for `arch` without cargo-swift to the rustflags of
+/// `settings`.
+///
+/// If `RUSTFLAGS` or `CARGO_ENCODED_RUSTFLAGS` are set, in the settings or the environment, cargo
+/// ignores all rustflags from config, so all rustflags are passed as `CARGO_ENCODED_RUSTFLAGS`
+/// and the rustflags of `settings` are cleared.
+pub fn inherit_rustflags(settings: &mut TargetSettings, arch: &str, config: &CargoConfig) {
+    if settings.rustflags.is_empty() {
+        return;
+    }
+
+    let settings_rustflags = env_rustflags(|key| settings.env.get(key).cloned());
+    if let Some(mut flags) = settings_rustflags.or_else(|| config.env_rustflags.clone()) {
+        flags.append(&mut settings.rustflags);
+        settings
+            .env
+            .insert("CARGO_ENCODED_RUSTFLAGS".to_owned(), flags.join("\x1f"));
+    } else if !config.has_target_rustflags(arch) {
+        let mut flags = config.build_rustflags.clone();
+        flags.append(&mut settings.rustflags);
+        settings.rustflags = flags;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARCH: &str = "aarch64-apple-ios";
+
+    fn with_rustflags(rustflags: &[&str]) -> TargetSettings {
+        TargetSettings {
+            rustflags: rustflags.iter().map(|flag| flag.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn parse_config(files: &[&str]) -> CargoConfig {
+        let mut config = CargoConfig::default();
+        for file in files {
+            config.add_file(file).unwrap();
+        }
+        config
+    }
+
+    #[test]
+    fn joins_arrays_and_replaces_strings_of_build_rustflags() {
+        let config = parse_config(&[
+            "build.rustflags = \"-C debuginfo=1\"",
+            "[build]\nrustflags = [\"--cfg\", \"home\"]",
+            "[build]\nrustflags = [\"--cfg\", \"project\"]",
+        ]);
+        assert_eq!(
+            config.build_rustflags,
+            ["-C", "debuginfo=1", "--cfg", "home", "--cfg", "project"]
+        );
+
+        let config = parse_config(&[
+            "build.rustflags = [\"--cfg\", \"home\"]",
+            "build.rustflags = \"-C opt-level=s\"",
+        ]);
+        assert_eq!(config.build_rustflags, ["-C", "opt-level=s"]);
+    }
+
+    #[test]
+    fn prepends_build_rustflags() {
+        let config = parse_config(&["build.rustflags = [\"--cfg\", \"build\"]"]);
+        let mut settings = with_rustflags(&["--cfg", "target"]);
+        inherit_rustflags(&mut settings, ARCH, &config);
+
+        assert_eq!(settings.rustflags, ["--cfg", "build", "--cfg", "target"]);
+        assert!(settings.env.is_empty());
+    }
+
+    #[test]
+    fn keeps_build_rustflags_ignored_by_cargo() {
+        let config = parse_config(&[
+            "build.rustflags = [\"--cfg\", \"build\"]",
+            "[target.aarch64-apple-ios]\nrustflags = [\"--cfg\", \"ios\"]",
+        ]);
+        let mut settings = with_rustflags(&["--cfg", "target"]);
+        inherit_rustflags(&mut settings, ARCH, &config);
+        assert_eq!(settings.rustflags, ["--cfg", "target"]);
+
+        let config = parse_config(&[
+            "build.rustflags = [\"--cfg\", \"build\"]",
+            "[target.'cfg(target_os = \"ios\")']\nrustflags = [\"--cfg\", \"ios\"]",
+        ]);
+        let mut settings = with_rustflags(&["--cfg", "target"]);
+        inherit_rustflags(&mut settings, ARCH, &config);
+        assert_eq!(settings.rustflags, ["--cfg", "target"]);
+
+        let config = parse_config(&[
+            "build.rustflags = [\"--cfg\", \"build\"]",
+            "[target.x86_64-apple-darwin]\nrustflags = [\"--cfg\", \"macos\"]",
+        ]);
+        let mut settings = with_rustflags(&["--cfg", "target"]);
+        inherit_rustflags(&mut settings, ARCH, &config);
+        assert_eq!(settings.rustflags, ["--cfg", "build", "--cfg", "target"]);
+    }
+
+    #[test]
+    fn encodes_rustflags_from_environment() {
+        let config = CargoConfig {
+            env_rustflags: env_rustflags(|key| {
+                (key == "RUSTFLAGS").then(|| "-C  debuginfo=1 ".to_owned())
+            }),
+            build_rustflags: vec!["--cfg".to_owned(), "build".to_owned()],
+            ..Default::default()
+        };
+        let mut settings = with_rustflags(&["--remap-path-prefix=/my dir=."]);
+        inherit_rustflags(&mut settings, ARCH, &config);
+
+        assert!(settings.rustflags.is_empty());
+        assert_eq!(
+            settings.env["CARGO_ENCODED_RUSTFLAGS"],
+            "-C\x1fdebuginfo=1\x1f--remap-path-prefix=/my dir=."
+        );
+    }
+
+    #[test]
+    fn prefers_rustflags_from_settings_environment() {
+        let config = CargoConfig {
+            env_rustflags: Some(vec!["--cfg".to_owned(), "env".to_owned()]),
+            ..Default::default()
+        };
+        let mut settings = with_rustflags(&["--cfg", "target"]);
+        settings.env.insert(
+            "CARGO_ENCODED_RUSTFLAGS".to_owned(),
+            "--cfg\x1fsettings".to_owned(),
+        );
+        inherit_rustflags(&mut settings, ARCH, &config);
+
+        assert_eq!(
+            settings.env["CARGO_ENCODED_RUSTFLAGS"],
+            "--cfg\x1fsettings\x1f--cfg\x1ftarget"
+        );
+    }
+
+    #[test]
+    fn leaves_settings_without_rustflags_alone() {
+        let config = CargoConfig {
+            env_rustflags: Some(vec!["--cfg".to_owned(), "env".to_owned()]),
+            ..Default::default()
+        };
+        let mut settings = with_rustflags(&[]);
+        inherit_rustflags(&mut settings, ARCH, &config);
+
+        assert!(settings.env.is_empty());
+    }
+}
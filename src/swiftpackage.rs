@@ -1,23 +1,361 @@
 use askama::Template;
-use glob::glob;
+use glob::{glob, Pattern};
 use itertools::Itertools;
-use std::fs::{copy, create_dir_all, write};
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::fs::{copy, create_dir_all, remove_dir_all, rename, write};
+use std::io;
+use std::path::{Path, PathBuf};
 
-use crate::{package, recreate_dir, templating, Result};
+use crate::version::Version;
+use crate::{package, recreate_dir, remove_dir_if_exists, sorted_dir_entries, templating, Result};
 
-/// Create artifacts for a swift package given the package name
+/// Additions to the generated Package.swift, declared in the `manifest` table of the package
+/// options
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ManifestSettings {
+    /// Linkage of the library product, left to SwiftPM if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_type: Option<ProductType>,
+    /// Packages whose products the module depends on
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<PackageDependency>,
+    #[serde(skip_serializing_if = "LinkerSettings::is_empty")]
+    pub linker_settings: LinkerSettings,
+    #[serde(skip_serializing_if = "SwiftSettings::is_empty")]
+    pub swift_settings: SwiftSettings,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProductType {
+    Static,
+    Dynamic,
+}
+
+/// A SwiftPM package, either fetched from `url` at one of the given versions or located at `path`
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PackageDependency {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Path of a local package, relative to the generated package
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Minimum version, allowing newer versions up to the next major version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exact: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// Identity of the package, defaults to the last component of `url` or `path`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    /// Products of the package the module depends on
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub products: Vec<String>,
+}
+
+/// System frameworks and libraries the module links against
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct LinkerSettings {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub frameworks: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub libraries: Vec<String>,
+}
+
+/// Settings for compiling the Swift sources of the module
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct SwiftSettings {
+    /// Swift language mode like `5` or `6`, requires swift-tools-version 6.0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_mode: Option<String>,
+    /// Enables complete concurrency checking in Swift 5 mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict_concurrency: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub upcoming_features: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub experimental_features: Vec<String>,
+    /// Compilation conditions for `#if`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub defines: Vec<String>,
+}
+
+impl ManifestSettings {
+    /// Fills every setting that is not set in `self` with the value from `fallback`
+    pub(crate) fn or(self, fallback: Self) -> Self {
+        Self {
+            product_type: self.product_type.or(fallback.product_type),
+            dependencies: or_vec(self.dependencies, fallback.dependencies),
+            linker_settings: self.linker_settings.or(fallback.linker_settings),
+            swift_settings: self.swift_settings.or(fallback.swift_settings),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Renders the settings to the Swift code of the manifest. Fails if a setting is invalid or
+    /// not supported by the given swift-tools-version.
+    pub(crate) fn swift_code(
+        &self,
+        swift_tools_version: &Version,
+    ) -> Result<templating::ManifestCode> {
+        let product_type = self.product_type.map(|t| match t {
+            ProductType::Static => ".static".to_owned(),
+            ProductType::Dynamic => ".dynamic".to_owned(),
+        });
+
+        let mut dependencies = Vec::new();
+        let mut target_dependencies = Vec::new();
+        for dependency in &self.dependencies {
+            dependencies.push(dependency.package_swift()?);
+            let package = dependency.identity()?;
+            target_dependencies.extend(dependency.products.iter().map(|product| {
+                format!(
+                    ".product(name: {}, package: {})",
+                    quoted(product),
+                    quoted(&package)
+                )
+            }));
+        }
+
+        let linker_settings = self
+            .linker_settings
+            .frameworks
+            .iter()
+            .map(|f| format!(".linkedFramework({})", quoted(f)))
+            .chain(
+                self.linker_settings
+                    .libraries
+                    .iter()
+                    .map(|l| format!(".linkedLibrary({})", quoted(l))),
+            )
+            .collect();
+
+        Ok(templating::ManifestCode {
+            product_type,
+            dependencies,
+            target_dependencies,
+            linker_settings,
+            swift_settings: self.swift_settings.package_swift(swift_tools_version)?,
+        })
+    }
+}
+
+impl PackageDependency {
+    fn package_swift(&self) -> Result<String> {
+        let requirements = [
+            ("from", &self.from),
+            ("exact", &self.exact),
+            ("branch", &self.branch),
+            ("revision", &self.revision),
+        ]
+        .into_iter()
+        .filter_map(|(label, value)| value.as_ref().map(|value| (label, value)))
+        .collect::<Vec<_>>();
+
+        match (&self.url, &self.path, requirements.as_slice()) {
+            (Some(url), None, [(label, value)]) => Ok(format!(
+                ".package(url: {}, {label}: {})",
+                quoted(url),
+                quoted(value)
+            )),
+            (Some(url), None, _) => Err(format!(
+                "Dependency {url} needs exactly one of `from`, `exact`, `branch` or `revision`!"
+            )
+            .into()),
+            (None, Some(path), []) => Ok(format!(".package(path: {})", quoted(path))),
+            (None, Some(path), _) => Err(format!(
+                "Dependency {path} is a local package and cannot have a version!"
+            )
+            .into()),
+            _ => Err("Every dependency needs either a `url` or a `path`!".into()),
+        }
+    }
+
+    /// Identity SwiftPM derives from the last component of the URL or path, e.g. `swift-log` for
+    /// `https://github.com/apple/swift-log.git`
+    fn identity(&self) -> Result<String> {
+        if let Some(package) = &self.package {
+            return Ok(package.clone());
+        }
+
+        self.url
+            .as_ref()
+            .or(self.path.as_ref())
+            .and_then(|location| location.trim_end_matches('/').rsplit('/').next())
+            .map(|name| name.trim_end_matches(".git").to_owned())
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| {
+                "Could not determine the package name of a dependency, please set `package`!".into()
+            })
+    }
+}
+
+impl LinkerSettings {
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            frameworks: or_vec(self.frameworks, fallback.frameworks),
+            libraries: or_vec(self.libraries, fallback.libraries),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.frameworks.is_empty() && self.libraries.is_empty()
+    }
+
+    /// Parses the output of `rustc --print native-static-libs`, e.g.
+    /// `-framework Security -liconv -lSystem -lc -lm`. The parts of libSystem are skipped, as
+    /// every binary for Apple platforms links it anyway. Also returns the arguments that are
+    /// neither a framework nor a library.
+    pub(crate) fn from_native_static_libs(native_static_libs: &str) -> (Self, Vec<String>) {
+        const LIB_SYSTEM: [&str; 3] = ["System", "c", "m"];
+
+        let mut settings = Self::default();
+        let mut unknown = Vec::new();
+        let mut args = native_static_libs.split_whitespace();
+        while let Some(arg) = args.next() {
+            if arg == "-framework" {
+                match args.next() {
+                    Some(framework) => settings.frameworks.push(framework.to_owned()),
+                    None => unknown.push(arg.to_owned()),
+                }
+            } else if let Some(library) = arg.strip_prefix("-l") {
+                if !LIB_SYSTEM.contains(&library) {
+                    settings.libraries.push(library.to_owned());
+                }
+            } else {
+                unknown.push(arg.to_owned());
+            }
+        }
+
+        (settings, unknown)
+    }
+
+    /// Adds the frameworks and libraries of `other` that are not in these settings yet
+    pub(crate) fn extend(&mut self, other: Self) {
+        for (names, other) in [
+            (&mut self.frameworks, other.frameworks),
+            (&mut self.libraries, other.libraries),
+        ] {
+            for name in other {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+    }
+}
+
+impl SwiftSettings {
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            language_mode: self.language_mode.or(fallback.language_mode),
+            strict_concurrency: self.strict_concurrency.or(fallback.strict_concurrency),
+            upcoming_features: or_vec(self.upcoming_features, fallback.upcoming_features),
+            experimental_features: or_vec(
+                self.experimental_features,
+                fallback.experimental_features,
+            ),
+            defines: or_vec(self.defines, fallback.defines),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn package_swift(&self, swift_tools_version: &Version) -> Result<Vec<String>> {
+        let mut settings = Vec::new();
+        if let Some(language_mode) = &self.language_mode {
+            if *swift_tools_version < Version::new(6, 0) {
+                Err("The Swift language mode requires a swift-tools-version of 6.0 or newer!")?;
+            }
+            let version: Version = language_mode
+                .parse()
+                .map_err(|e| format!("Invalid Swift language mode: {e}"))?;
+            let constant = match version.minor {
+                0 => format!("v{}", version.major),
+                minor => format!("v{}_{minor}", version.major),
+            };
+            settings.push(format!(".swiftLanguageMode(.{constant})"));
+        }
+
+        let mut upcoming_features = self.upcoming_features.clone();
+        if self.strict_concurrency.unwrap_or_default() {
+            upcoming_features.push("StrictConcurrency".to_owned());
+        }
+        let features = upcoming_features
+            .iter()
+            .map(|f| format!(".enableUpcomingFeature({})", quoted(f)))
+            .chain(
+                self.experimental_features
+                    .iter()
+                    .map(|f| format!(".enableExperimentalFeature({})", quoted(f))),
+            )
+            .collect::<Vec<_>>();
+        if !features.is_empty() && *swift_tools_version < Version::new(5, 8) {
+            Err("Upcoming and experimental Swift features require a swift-tools-version of 5.8 or newer!")?;
+        }
+        settings.extend(features);
+        settings.extend(
+            self.defines
+                .iter()
+                .map(|d| format!(".define({})", quoted(d))),
+        );
+
+        Ok(settings)
+    }
+}
+
+/// Lists from the options take precedence over lists from the fallback as a whole
+fn or_vec<T>(values: Vec<T>, fallback: Vec<T>) -> Vec<T> {
+    if values.is_empty() {
+        fallback
+    } else {
+        values
+    }
+}
+
+/// Swift string literal of `value`. The escapes of Rust strings are valid in Swift as well.
+fn quoted(value: &str) -> String {
+    format!("{value:?}")
+}
+
+/// Create artifacts for a swift package given the package name in `output_dir`, including the
+/// Swift files generated into `generated_sources_dir`. Hand-written Swift sources and resources
+/// are merged into the module sources as well.
 ///
-/// **Note**: This method assumes that a directory with the package name and the .xcframework already exists
+/// **Note**: This method assumes that `output_dir` and the .xcframework in it already exist
+#[allow(clippy::too_many_arguments)]
 pub fn create_swiftpackage(
     package_name: &str,
+    output_dir: &Path,
+    generated_sources_dir: &Path,
     xcframework_name: &str,
     disable_warnings: bool,
     platforms: &[package::PlatformSpec],
     swift_tools_version: &str,
     privacy_manifest: Option<&Path>,
+    remote: Option<&templating::RemoteBinaryTarget>,
+    swift_sources: Option<&Path>,
+    swift_resources: Option<&Path>,
+    manifest: &templating::ManifestCode,
 ) -> Result<()> {
-    let platforms = &platforms.iter().map(|p| p.package_swift()).join(", ");
+    let tools_version = swift_tools_version.parse()?;
+    let platforms = &platforms
+        .iter()
+        .map(|p| p.package_swift(&tools_version))
+        .join(", ");
     // TODO: Instead of assuming the directory and the xcframework, let this manage directory
     //  recreation and let it copy the xcframework
     let package_manifest = templating::PackageSwift {
@@ -26,18 +364,32 @@ pub fn create_swiftpackage(
         disable_warnings,
         platforms,
         swift_tools_version,
+        remote,
+        has_resources: swift_resources.is_some(),
+        manifest,
     };
 
     write(
-        format!("{}/Package.swift", package_name),
+        output_dir.join("Package.swift"),
         package_manifest.render().unwrap(),
     )
     .map_err(|e| format!("Could not write Package.swift: \n {e}"))?;
 
-    create_dir_all(format!("{}/Sources/{}", package_name, package_name))
+    // The zipped XCFramework is uploaded instead of committed with the package
+    if remote.is_some() {
+        write(
+            output_dir.join(".gitignore"),
+            format!("/{xcframework_name}.xcframework.zip\n"),
+        )
+        .map_err(|e| format!("Could not write .gitignore: \n {e}"))?;
+    }
+
+    let sources_dir = output_dir.join("Sources").join(package_name);
+    create_dir_all(&sources_dir)
         .map_err(|e| format!("Could not create module sources directory: \n {e}"))?;
 
-    for swift_file in glob("./generated/sources/*.swift")
+    let pattern = Pattern::escape(&generated_sources_dir.to_string_lossy());
+    for swift_file in glob(&format!("{pattern}/*.swift"))
         .map_err(|e| format!("Could not find generated swift source files: \n {e}"))?
     {
         let swift_file = swift_file
@@ -48,23 +400,440 @@ pub fn create_swiftpackage(
             .to_str()
             .ok_or("Could not convert file name to string")?
             .to_string();
-        copy(
-            swift_file,
-            format!("{}/Sources/{}/{}", package_name, package_name, file_name),
-        )
-        .map_err(|e| format!("Could not copy generated swift source files: \n {e}"))?;
+        copy(swift_file, sources_dir.join(file_name))
+            .map_err(|e| format!("Could not copy generated swift source files: \n {e}"))?;
+    }
+
+    if let Some(swift_sources) = swift_sources {
+        merge_dir(swift_sources, &sources_dir, "Swift sources")?;
+    }
+    if let Some(swift_resources) = swift_resources {
+        merge_dir(
+            swift_resources,
+            &sources_dir.join("Resources"),
+            "Swift resources",
+        )?;
     }
 
     if let Some(manifest) = privacy_manifest {
-        copy(manifest, format!("{}/PrivacyInfo.xcprivacy", package_name))
+        copy(manifest, output_dir.join("PrivacyInfo.xcprivacy"))
             .map_err(|e| format!("Could not copy privacy manifest: \n {e}"))?;
     }
 
     Ok(())
 }
 
-pub fn recreate_output_dir(package_name: &str) -> Result<()> {
-    let dir = format!("./{package_name}");
+/// Copies all files in `src` and its subdirectories into `dst`. Fails without copying anything if
+/// a file already exists in `dst`, or if a Swift file has the same name as a Swift file directly in
+/// `dst`, which the Swift compiler rejects even in different directories.
+fn merge_dir(src: &Path, dst: &Path, kind: &str) -> Result<()> {
+    let mut files = Vec::new();
+    collect_files(src, Path::new(""), &mut files)
+        .map_err(|e| format!("Could not read {kind} in {}: \n {e}", src.display()))?;
+
+    let collisions = files
+        .iter()
+        .filter(|file| {
+            let is_swift = file.extension().is_some_and(|ext| ext == "swift");
+            dst.join(file).exists()
+                || (is_swift && file.file_name().is_some_and(|name| dst.join(name).exists()))
+        })
+        .map(|file| file.display().to_string())
+        .collect::<Vec<_>>();
+    if !collisions.is_empty() {
+        Err(format!(
+            "{kind} in {} collide with generated files in {}: \n {}",
+            src.display(),
+            dst.display(),
+            collisions.join("\n ")
+        ))?;
+    }
+
+    for file in files {
+        let target = dst.join(&file);
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)
+                .map_err(|e| format!("Could not create directory for {kind}: \n {e}"))?;
+        }
+        copy(src.join(&file), &target)
+            .map_err(|e| format!("Could not copy {kind} file {}: \n {e}", file.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Collects the paths of all files in `dir` and its subdirectories relative to `dir`, in sorted
+/// order. Hidden files like .DS_Store are skipped.
+fn collect_files(dir: &Path, prefix: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in sorted_dir_entries(dir.join(prefix))? {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = prefix.join(name);
+        if entry.path().is_dir() {
+            collect_files(dir, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Directory next to `output_dir` that the package is assembled in
+pub fn staging_dir(output_dir: &Path) -> PathBuf {
+    sibling_dir(output_dir, ".", ".staging")
+}
+
+/// Creates an empty staging directory to assemble the package in, so that the previous package
+/// in `output_dir` stays untouched until every step succeeded
+pub fn create_staging_dir(output_dir: &Path) -> Result<PathBuf> {
+    let staging_dir = staging_dir(output_dir);
+    recreate_dir(&staging_dir)
+        .map_err(|e| format!("Could not create staging directory: \n {e}"))?;
+
+    Ok(staging_dir)
+}
+
+/// Removes a staging directory left behind by a failed run
+pub fn remove_staging_dir(staging_dir: &Path) -> Result<()> {
+    remove_dir_if_exists(staging_dir)
+        .map_err(|e| format!("Could not remove staging directory: \n {e}").into())
+}
+
+/// Replaces `output_dir` with the assembled `staging_dir`. The previous package is moved to
+/// `<output_dir>.bak` if `keep_backup` is set and removed otherwise.
+pub fn replace_output_dir(staging_dir: &Path, output_dir: &Path, keep_backup: bool) -> Result<()> {
+    let previous_dir = if keep_backup {
+        sibling_dir(output_dir, "", ".bak")
+    } else {
+        sibling_dir(output_dir, ".", ".old")
+    };
+    remove_dir_if_exists(&previous_dir)
+        .map_err(|e| format!("Could not remove {}: \n {e}", previous_dir.display()))?;
+
+    let has_previous = output_dir.exists();
+    if has_previous {
+        rename(output_dir, &previous_dir)
+            .map_err(|e| format!("Could not move the previous package aside: \n {e}"))?;
+    }
+    if let Err(e) = rename(staging_dir, output_dir) {
+        if has_previous {
+            // Put the previous package back, so that a failed swap leaves it in place
+            let _ = rename(&previous_dir, output_dir);
+        }
+        return Err(format!("Could not move the new package into place: \n {e}").into());
+    }
+    if has_previous && !keep_backup {
+        remove_dir_all(&previous_dir)
+            .map_err(|e| format!("Could not remove the previous package: \n {e}"))?;
+    }
+
+    Ok(())
+}
+
+/// `<parent>/<prefix><name of dir><suffix>`, which is on the same file system as `dir` and can
+/// therefore be renamed to it
+fn sibling_dir(dir: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let name = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    dir.with_file_name(format!("{prefix}{name}{suffix}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "cargo-swift-swiftpackage-{name}-{}",
+            std::process::id()
+        ));
+        recreate_dir(&dir).unwrap();
+        dir
+    }
+
+    fn write_files(dir: &Path, files: &[&str]) {
+        for file in files {
+            let path = dir.join(file);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+    }
+
+    fn relative_files(dir: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        collect_files(dir, Path::new(""), &mut files).unwrap();
+        files
+            .into_iter()
+            .map(|file| file.display().to_string())
+            .collect()
+    }
+
+    fn manifest_code() -> templating::ManifestCode {
+        templating::ManifestCode {
+            product_type: None,
+            dependencies: vec![],
+            target_dependencies: vec![],
+            linker_settings: vec![],
+            swift_settings: vec![],
+        }
+    }
+
+    fn render(has_resources: bool, manifest: &templating::ManifestCode) -> String {
+        templating::PackageSwift {
+            package_name: "Greeter",
+            xcframework_name: "GreeterFFI",
+            disable_warnings: false,
+            platforms: ".iOS(.v13)",
+            swift_tools_version: "5.5",
+            remote: None,
+            has_resources,
+            manifest,
+        }
+        .render()
+        .unwrap()
+    }
+
+    #[test]
+    fn merges_nested_files() {
+        let dir = test_dir("merge");
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        write_files(
+            &src,
+            &["Extensions.swift", "Models/User.swift", ".DS_Store"],
+        );
+        write_files(&dst, &["Greeter.swift"]);
+
+        merge_dir(&src, &dst, "Swift sources").unwrap();
+
+        assert_eq!(
+            relative_files(&dst),
+            ["Extensions.swift", "Greeter.swift", "Models/User.swift"]
+        );
+        assert_eq!(
+            fs::read_to_string(dst.join("Models/User.swift")).unwrap(),
+            "Models/User.swift"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_colliding_files_without_copying() {
+        let dir = test_dir("collision");
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        write_files(&src, &["A.swift", "Greeter.swift", "data/config.json"]);
+        write_files(&dst, &["Greeter.swift", "data/config.json"]);
+
+        let error = merge_dir(&src, &dst, "Swift sources").unwrap_err();
+
+        let message = format!("{error:?}");
+        assert!(message.contains("Greeter.swift"), "{message}");
+        assert!(message.contains("data/config.json"), "{message}");
+        assert!(!message.contains("A.swift"), "{message}");
+        assert!(!dst.join("A.swift").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_swift_files_named_like_generated_files_in_subdirectories() {
+        let dir = test_dir("swift-names");
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        write_files(
+            &src,
+            &["Extensions/Greeter.swift", "Resources/Greeter.json"],
+        );
+        write_files(&dst, &["Greeter.swift", "Greeter.json"]);
 
-    recreate_dir(dir)
+        let error = merge_dir(&src, &dst, "Swift sources").unwrap_err();
+
+        let message = format!("{error:?}");
+        assert!(message.contains("Extensions/Greeter.swift"), "{message}");
+        assert!(!message.contains("Resources/Greeter.json"), "{message}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renders_resources() {
+        let manifest = manifest_code();
+
+        assert!(!render(false, &manifest).contains("resources:"));
+        assert!(render(true, &manifest).contains(
+            "            ],
+            resources: [
+                .process(\"Resources\"),
+            ]
+        ),"
+        ));
+    }
+
+    #[test]
+    fn parses_native_static_libs() {
+        let (settings, unknown) = LinkerSettings::from_native_static_libs(
+            "-framework Security -framework CoreFoundation -liconv -lSystem -lc -lm -L/opt/lib -framework",
+        );
+
+        assert_eq!(settings.frameworks, ["Security", "CoreFoundation"]);
+        assert_eq!(settings.libraries, ["iconv"]);
+        assert_eq!(unknown, ["-L/opt/lib", "-framework"]);
+    }
+
+    #[test]
+    fn extends_linker_settings_without_duplicates() {
+        let mut settings = LinkerSettings {
+            frameworks: vec!["Security".to_owned()],
+            libraries: vec!["sqlite3".to_owned()],
+        };
+        settings.extend(LinkerSettings {
+            frameworks: vec!["SystemConfiguration".to_owned(), "Security".to_owned()],
+            libraries: vec!["sqlite3".to_owned(), "iconv".to_owned()],
+        });
+
+        assert_eq!(settings.frameworks, ["Security", "SystemConfiguration"]);
+        assert_eq!(settings.libraries, ["sqlite3", "iconv"]);
+    }
+
+    #[test]
+    fn renders_swift_and_linker_settings() {
+        let settings = ManifestSettings {
+            linker_settings: LinkerSettings {
+                frameworks: vec!["Security".to_owned()],
+                libraries: vec!["iconv".to_owned()],
+            },
+            swift_settings: SwiftSettings {
+                language_mode: Some("6".to_owned()),
+                strict_concurrency: Some(true),
+                experimental_features: vec!["AccessLevelOnImport".to_owned()],
+                defines: vec!["FFI".to_owned()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let manifest = settings.swift_code(&Version::new(6, 0)).unwrap();
+        let package_swift = templating::PackageSwift {
+            package_name: "Greeter",
+            xcframework_name: "GreeterFFI",
+            disable_warnings: true,
+            platforms: ".iOS(.v13)",
+            swift_tools_version: "6.0",
+            remote: None,
+            has_resources: false,
+            manifest: &manifest,
+        }
+        .render()
+        .unwrap();
+
+        assert!(package_swift.contains(
+            r#"            ],
+            swiftSettings: [
+                .swiftLanguageMode(.v6),
+                .enableUpcomingFeature("StrictConcurrency"),
+                .enableExperimentalFeature("AccessLevelOnImport"),
+                .define("FFI"),
+                .unsafeFlags(["-suppress-warnings"]),
+            ],
+            linkerSettings: [
+                .linkedFramework("Security"),
+                .linkedLibrary("iconv"),
+            ]
+        ),"#
+        ));
+        assert!(!render(false, &manifest_code()).contains("Settings:"));
+    }
+
+    #[test]
+    fn rejects_swift_settings_unknown_to_swift_tools_version() {
+        let language_mode = ManifestSettings {
+            swift_settings: SwiftSettings {
+                language_mode: Some("6".to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(language_mode.swift_code(&Version::new(5, 10)).is_err());
+
+        let upcoming_features = ManifestSettings {
+            swift_settings: SwiftSettings {
+                upcoming_features: vec!["ExistentialAny".to_owned()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(upcoming_features.swift_code(&Version::new(5, 7)).is_err());
+        assert!(upcoming_features.swift_code(&Version::new(5, 8)).is_ok());
+    }
+
+    #[test]
+    fn moves_package_into_place_without_previous_package() {
+        let dir = test_dir("replace-new");
+        let staging_dir = dir.join(".Package.staging");
+        let output_dir = dir.join("Package");
+        write_files(&staging_dir, &["Package.swift"]);
+
+        replace_output_dir(&staging_dir, &output_dir, false).unwrap();
+
+        assert_eq!(relative_files(&output_dir), ["Package.swift"]);
+        assert!(!staging_dir.exists());
+        assert_eq!(relative_files(&dir), ["Package/Package.swift"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_previous_package() {
+        let dir = test_dir("replace-previous");
+        let staging_dir = dir.join(".Package.staging");
+        let output_dir = dir.join("Package");
+        write_files(&staging_dir, &["Sources/New.swift"]);
+        write_files(&output_dir, &["Sources/Old.swift"]);
+
+        replace_output_dir(&staging_dir, &output_dir, false).unwrap();
+
+        assert_eq!(relative_files(&dir), ["Package/Sources/New.swift"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_previous_package_as_backup_if_requested() {
+        let dir = test_dir("replace-backup");
+        let staging_dir = dir.join(".Package.staging");
+        let output_dir = dir.join("Package");
+        write_files(&staging_dir, &["Sources/New.swift"]);
+        write_files(&output_dir, &["Sources/Old.swift"]);
+        write_files(&dir.join("Package.bak"), &["Sources/Older.swift"]);
+
+        replace_output_dir(&staging_dir, &output_dir, true).unwrap();
+
+        assert_eq!(
+            relative_files(&dir),
+            ["Package/Sources/New.swift", "Package.bak/Sources/Old.swift"]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restores_previous_package_if_new_package_cannot_be_moved() {
+        let dir = test_dir("replace-failed");
+        let output_dir = dir.join("Package");
+        write_files(&output_dir, &["Sources/Old.swift"]);
+
+        for keep_backup in [false, true] {
+            // The staging dir does not exist, so moving it into place fails
+            let result =
+                replace_output_dir(&dir.join(".Package.staging"), &output_dir, keep_backup);
+
+            assert!(result.is_err());
+            assert_eq!(relative_files(&dir), ["Package/Sources/Old.swift"]);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
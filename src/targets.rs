@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::process::Stdio;
 use std::{fmt::Display, process::Command};
 
@@ -6,9 +6,15 @@ use execute::command;
 use execute::Execute;
 use nonempty::{nonempty, NonEmpty};
 
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::{Message, PackageId};
+
 use crate::lib_type::LibType;
-use crate::metadata::{metadata, MetadataExt};
-use crate::package::FeatureOptions;
+use crate::package::{CargoOptions, TargetSettings};
+use crate::sha256::Sha256;
+use crate::universal::create_universal_binary;
+use crate::version::Version;
+use crate::Result;
 
 /// Queries `rustup target list` for both the default and nightly toolchains,
 /// caching which targets are available and installed on each. When any
@@ -117,17 +123,25 @@ pub enum Target {
     },
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Mode {
     Debug,
     Release,
+    /// Build with the cargo profile of the given name (`--profile <name>`)
+    Profile(String),
 }
 
+/// Formats the name of the directory cargo puts artifacts of this mode in
 impl Display for Mode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Mode::Debug => write!(f, "debug"),
             Mode::Release => write!(f, "release"),
+            Mode::Profile(profile) => match profile.as_str() {
+                "dev" | "test" => write!(f, "debug"),
+                "release" | "bench" => write!(f, "release"),
+                custom => write!(f, "{custom}"),
+            },
         }
     }
 }
@@ -135,37 +149,69 @@ impl Display for Mode {
 impl Target {
     fn cargo_build_commands(
         &self,
-        mode: Mode,
-        features: &FeatureOptions,
+        mode: &Mode,
+        cargo_options: &CargoOptions,
+        target_settings: &HashMap<&str, TargetSettings>,
         toolchain_targets: &ToolchainTargets,
+        manifest_path: &Utf8Path,
+        native_static_libs: Option<&NativeStaticLibs>,
     ) -> Vec<Command> {
+        // Only `cargo rustc` can pass the print request to the library alone, passing it in the
+        // rustflags would rebuild every dependency
+        let subcommand = match native_static_libs {
+            Some(_) => "rustc",
+            None => "build",
+        };
         self.architectures()
             .into_iter()
             .map(|arch| {
                 let mut cmd = if toolchain_targets.needs_build_std(arch) {
-                    command("cargo +nightly build -Z build-std")
+                    command(format!("cargo +nightly {subcommand} -Z build-std"))
                 } else if toolchain_targets.use_nightly() {
-                    command("cargo +nightly build")
+                    command(format!("cargo +nightly {subcommand}"))
                 } else {
-                    command("cargo build")
+                    command(format!("cargo {subcommand}"))
                 };
+                // Builds only the packaged crate, regardless of the current directory
+                cmd.arg("--manifest-path").arg(manifest_path);
+                if native_static_libs.is_some() {
+                    cmd.arg("--lib");
+                }
                 cmd.arg("--target").arg(arch);
+                cmd.arg("--message-format=json-render-diagnostics");
 
                 match mode {
                     Mode::Debug => {}
                     Mode::Release => {
                         cmd.arg("--release");
                     }
+                    Mode::Profile(profile) => {
+                        cmd.arg("--profile").arg(profile);
+                    }
                 }
 
-                if let Some(features) = &features.features {
-                    cmd.arg("--features").arg(features.join(","));
+                let settings = target_settings.get(arch);
+                if let Some(settings) = settings {
+                    cmd.args(settings.features.args());
                 }
-                if features.all_features {
-                    cmd.arg("--all-features");
+                cmd.args(cargo_options.args());
+
+                if let Some(settings) = settings {
+                    if !settings.rustflags.is_empty() {
+                        // Passed as target-specific config, so rustflags from cargo config files
+                        // are extended instead of replaced
+                        let rustflags = toml_edit::Array::from_iter(&settings.rustflags);
+                        cmd.arg("--config")
+                            .arg(format!("target.{arch}.rustflags={rustflags}"));
+                    }
+                    cmd.envs(&settings.env);
                 }
-                if features.no_default_features {
-                    cmd.arg("--no-default-features");
+                cmd.args(&cargo_options.build_args);
+                if let Some(native_static_libs) = native_static_libs {
+                    cmd.arg("--").arg(format!(
+                        "--print=native-static-libs={}",
+                        native_static_libs.path(arch)
+                    ));
                 }
 
                 cmd
@@ -173,47 +219,54 @@ impl Target {
             .collect()
     }
 
-    fn lipo_commands(&self, lib_name: &str, mode: Mode, lib_type: LibType) -> Vec<Command> {
+    /// Merges the libraries built for each architecture of a universal target into a single
+    /// universal library at `library_path`. Does nothing for single architecture targets.
+    pub fn create_universal_library(
+        &self,
+        artifacts: &Artifacts,
+        target_dir: &Utf8Path,
+        mode: &Mode,
+    ) -> Result<()> {
         match self {
-            Target::Single { .. } => vec![],
+            Target::Single { .. } => Ok(()),
             Target::Universal { architectures, .. } => {
-                let path = self.library_directory(mode);
-
-                let target = metadata().target_dir();
-                let target_name = library_file_name(lib_name, lib_type);
-                let component_paths: Vec<_> = architectures
+                let component_paths = architectures
                     .iter()
-                    .map(|arch| format!("{target}/{arch}/{mode}/{target_name}"))
-                    .collect();
-                let args = component_paths.join(" ");
-                let target_path = self.library_path(lib_name, mode, lib_type);
-
-                let make_dir = command(format!("mkdir -p {path}"));
-                let lipo = command(format!("lipo {args} -create -output {target_path}"));
-                vec![make_dir, lipo]
+                    .map(|arch| artifacts.library(arch))
+                    .collect::<Result<Vec<_>>>()?;
+                let target_path = self.library_path(artifacts, target_dir, mode)?;
+
+                create_universal_binary(&component_paths, target_path.as_std_path())
             }
         }
     }
 
-    /// Generates all commands necessary to build this target
+    /// Generates all commands necessary to build the crate of the Cargo.toml at `manifest_path`
+    /// for this target
     ///
     /// This function returns a list of commands that should be executed in their given
-    /// order to build this target (and bundle architecture targets with lipo if it is a universal target).
+    /// order to build this target. If this is a universal target, the built libraries need to be
+    /// merged with `create_universal_library` afterwards.
     ///
-    /// Note: `install_name_tool` for dynamic libs is handled during framework bundling
+    /// Note: the install name of dynamic libs is set during framework bundling
     /// in `xcframework::create_framework_bundle()`, where the framework name is known.
     pub fn commands(
         &self,
-        lib_name: &str,
-        mode: Mode,
-        lib_type: LibType,
-        features: &FeatureOptions,
+        mode: &Mode,
+        cargo_options: &CargoOptions,
+        target_settings: &HashMap<&str, TargetSettings>,
         toolchain_targets: &ToolchainTargets,
+        manifest_path: &Utf8Path,
+        native_static_libs: Option<&NativeStaticLibs>,
     ) -> Vec<Command> {
-        self.cargo_build_commands(mode, features, toolchain_targets)
-            .into_iter()
-            .chain(self.lipo_commands(lib_name, mode, lib_type))
-            .collect()
+        self.cargo_build_commands(
+            mode,
+            cargo_options,
+            target_settings,
+            toolchain_targets,
+            manifest_path,
+            native_static_libs,
+        )
     }
 
     /// Returns the names of all target architectures for this target
@@ -227,6 +280,36 @@ impl Target {
         }
     }
 
+    /// Returns the Apple names (as used by `lipo` and `xcodebuild`) of all architectures of
+    /// this target in alphabetical order
+    pub fn apple_architectures(&self) -> Vec<&'static str> {
+        let mut architectures: Vec<_> = self
+            .architectures()
+            .into_iter()
+            .map(|arch| match arch.split('-').next() {
+                Some("aarch64") => "arm64",
+                Some(arch) => arch,
+                None => arch,
+            })
+            .collect();
+        architectures.sort_unstable();
+        architectures
+    }
+
+    /// Returns the name of the directory containing this target inside an XCFramework,
+    /// e.g. `ios-arm64_x86_64-simulator`
+    pub fn library_identifier(&self) -> String {
+        let platform = self.platform();
+        let architectures = self.apple_architectures().join("_");
+        match platform.xcframework_variant() {
+            Some(variant) => format!(
+                "{}-{architectures}-{variant}",
+                platform.xcframework_platform()
+            ),
+            None => format!("{}-{architectures}", platform.xcframework_platform()),
+        }
+    }
+
     pub fn display_name(&self) -> &'static str {
         match self {
             Target::Single { display_name, .. } => display_name,
@@ -241,34 +324,140 @@ impl Target {
         }
     }
 
-    pub fn library_directory(&self, mode: Mode) -> String {
-        let mode = match mode {
-            Mode::Debug => "debug",
-            Mode::Release => "release",
-        };
-
-        let target = metadata().target_dir();
+    pub fn library_directory(&self, target_dir: &Utf8Path, mode: &Mode) -> String {
+        match self {
+            Target::Single { architecture, .. } => format!("{target_dir}/{architecture}/{mode}"),
+            Target::Universal { universal_name, .. } => {
+                format!("{target_dir}/{universal_name}/{mode}")
+            }
+        }
+    }
 
+    /// Returns the path of the library for this target. This is the library built by cargo for
+    /// single architecture targets and the merged library in `library_directory` for universal
+    /// targets.
+    pub fn library_path(
+        &self,
+        artifacts: &Artifacts,
+        target_dir: &Utf8Path,
+        mode: &Mode,
+    ) -> Result<Utf8PathBuf> {
         match self {
-            Target::Single { architecture, .. } => format!("{target}/{architecture}/{mode}"),
-            Target::Universal { universal_name, .. } => format!("{target}/{universal_name}/{mode}"),
+            Target::Single { architecture, .. } => Ok(artifacts.library(architecture)?.to_owned()),
+            Target::Universal { architectures, .. } => {
+                let library = artifacts.library(architectures.first())?;
+                let file_name = library
+                    .file_name()
+                    .ok_or_else(|| format!("Invalid library path {library}"))?;
+
+                Ok(Utf8PathBuf::from(self.library_directory(target_dir, mode)).join(file_name))
+            }
         }
     }
+}
 
-    pub fn library_path(&self, lib_name: &str, mode: Mode, lib_type: LibType) -> String {
-        format!(
-            "{}/{}",
-            self.library_directory(mode),
-            library_file_name(lib_name, lib_type)
-        )
+/// Makes the build write the native libraries and frameworks that the static library of a crate
+/// links against to `<dir>/<arch>-<fingerprint>.txt`, see `rustc --print native-static-libs`.
+///
+/// The files are only rewritten when rustc recompiles the library, so cargo can reuse a library
+/// without writing its file. Each file is named after the options of the build, so a reused
+/// library never reads back the file of a build with other features, flags or profile.
+pub struct NativeStaticLibs<'a> {
+    /// Absolute directory for the files, as rustc does not run in the current directory
+    pub dir: &'a Utf8Path,
+    fingerprints: HashMap<&'static str, String>,
+}
+
+impl<'a> NativeStaticLibs<'a> {
+    pub fn new(
+        dir: &'a Utf8Path,
+        mode: &Mode,
+        cargo_options: &CargoOptions,
+        target_settings: &HashMap<&'static str, TargetSettings>,
+    ) -> Self {
+        let fingerprints = target_settings
+            .iter()
+            .map(|(arch, settings)| {
+                let mut hasher = Sha256::default();
+                let options = [mode.to_string()]
+                    .into_iter()
+                    .chain(cargo_options.args())
+                    .chain(cargo_options.build_args.iter().cloned())
+                    .chain(settings.features.args())
+                    .chain(settings.rustflags.iter().cloned())
+                    .chain(
+                        settings
+                            .env
+                            .iter()
+                            .map(|(key, value)| format!("{key}={value}")),
+                    );
+                for option in options {
+                    hasher.update(option.as_bytes());
+                    hasher.update(&[0]);
+                }
+                (*arch, hasher.finish_hex()[..16].to_owned())
+            })
+            .collect();
+
+        Self { dir, fingerprints }
     }
+
+    /// Path of the file for `arch`
+    pub fn path(&self, arch: &str) -> Utf8PathBuf {
+        match self.fingerprints.get(arch) {
+            Some(fingerprint) => self.dir.join(format!("{arch}-{fingerprint}.txt")),
+            None => self.dir.join(format!("{arch}.txt")),
+        }
+    }
+}
+
+/// Libraries built by cargo for each architecture, as reported in the `compiler-artifact` messages
+/// of `cargo build --message-format=json-render-diagnostics`
+#[derive(Debug, Default)]
+pub struct Artifacts {
+    libraries: HashMap<&'static str, Utf8PathBuf>,
 }
 
-pub fn library_file_name(lib_name: &str, lib_type: LibType) -> String {
-    format!("lib{}.{}", lib_name, lib_type.file_extension())
+impl Artifacts {
+    /// Records the library of the given package and type from the JSON `messages` of a cargo build
+    /// for `arch`
+    pub fn add(
+        &mut self,
+        arch: &'static str,
+        messages: &[u8],
+        package: &PackageId,
+        lib_type: LibType,
+    ) -> Result<()> {
+        let library = Message::parse_stream(messages)
+            .filter_map(|message| match message {
+                Ok(Message::CompilerArtifact(artifact)) if artifact.package_id == *package => {
+                    Some(artifact.filenames)
+                }
+                _ => None,
+            })
+            .flatten()
+            .find(|filename| filename.extension() == Some(lib_type.file_extension()))
+            .ok_or_else(|| {
+                format!(
+                    "cargo did not report a {lib_type} library (.{}) built for {arch}",
+                    lib_type.file_extension()
+                )
+            })?;
+
+        self.libraries.insert(arch, library);
+        Ok(())
+    }
+
+    /// Returns the path of the library built for `arch`
+    pub fn library(&self, arch: &str) -> Result<&Utf8Path> {
+        self.libraries
+            .get(arch)
+            .map(Utf8PathBuf::as_path)
+            .ok_or_else(|| format!("No library was built for {arch}").into())
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ApplePlatform {
     IOS,
     IOSSimulator,
@@ -292,11 +481,6 @@ pub struct PlatformInfoPlist {
     /// "LSMinimumSystemVersion" (macOS / Mac Catalyst — Apple treats Catalyst
     /// bundles as macOS bundles for Info.plist purposes).
     pub version_key: &'static str,
-    /// Deployment-target env var whose value supplies `version_key`.
-    pub version_env_var: &'static str,
-    /// Fallback used when the env var is unset; matches the lower bound of
-    /// `PlatformSpec::package_swift`'s defaults.
-    pub default_version: &'static str,
     /// Value of the `CFBundleSupportedPlatforms` single-element array.
     pub supported_platform: &'static str,
     /// UIDeviceFamily integer values (empty for macOS, where the key is n/a).
@@ -311,6 +495,82 @@ impl ApplePlatform {
         matches!(self, ApplePlatform::MacOS | ApplePlatform::MacCatalyst)
     }
 
+    /// Environment variable rustc reads the deployment target of this platform from.
+    /// Mac Catalyst deployment targets are iOS versions.
+    pub fn deployment_target_env_var(&self) -> &'static str {
+        use ApplePlatform::*;
+        match self {
+            IOS | IOSSimulator | MacCatalyst => "IPHONEOS_DEPLOYMENT_TARGET",
+            MacOS => "MACOSX_DEPLOYMENT_TARGET",
+            TvOS | TvOSSimulator => "TVOS_DEPLOYMENT_TARGET",
+            WatchOS | WatchOSSimulator => "WATCHOS_DEPLOYMENT_TARGET",
+            VisionOS | VisionOSSimulator => "XROS_DEPLOYMENT_TARGET",
+        }
+    }
+
+    /// Returns the lowest deployment target rustc supports for the given architecture of this
+    /// platform
+    pub fn minimum_deployment_target(&self, arch: &str) -> Version {
+        use ApplePlatform::*;
+        let is_arm64 = arch.starts_with("aarch64");
+        match self {
+            MacOS if is_arm64 => Version::new(11, 0),
+            MacOS => Version::new(10, 12),
+            IOSSimulator | MacCatalyst | TvOSSimulator if is_arm64 => Version::new(14, 0),
+            MacCatalyst => Version::new(13, 1),
+            IOS | IOSSimulator | TvOS | TvOSSimulator => Version::new(10, 0),
+            WatchOSSimulator if is_arm64 => Version::new(7, 0),
+            WatchOS | WatchOSSimulator => Version::new(5, 0),
+            VisionOS | VisionOSSimulator => Version::new(1, 0),
+        }
+    }
+
+    /// Returns the minimum version for the framework Info.plist of this platform built with
+    /// the given deployment target. Mac Catalyst bundles declare the macOS version that
+    /// shipped with the iOS version of the deployment target.
+    pub fn info_plist_version(&self, deployment_target: &str) -> String {
+        if !matches!(self, ApplePlatform::MacCatalyst) {
+            return deployment_target.to_owned();
+        }
+
+        let major = deployment_target
+            .split('.')
+            .next()
+            .and_then(|major| major.parse::<u32>().ok())
+            .unwrap_or(13);
+        match major {
+            ..=13 => "10.15".to_owned(),
+            // iOS 14 shipped with macOS 11, up to iOS 18 with macOS 15
+            14..=18 => format!("{}.0", major - 3),
+            // Since 26, both use the same version numbers
+            _ => format!("{major}.0"),
+        }
+    }
+
+    /// Value of `SupportedPlatform` in the Info.plist of an XCFramework
+    pub fn xcframework_platform(&self) -> &'static str {
+        use ApplePlatform::*;
+        match self {
+            IOS | IOSSimulator | MacCatalyst => "ios",
+            MacOS => "macos",
+            TvOS | TvOSSimulator => "tvos",
+            WatchOS | WatchOSSimulator => "watchos",
+            VisionOS | VisionOSSimulator => "xros",
+        }
+    }
+
+    /// Value of `SupportedPlatformVariant` in the Info.plist of an XCFramework, if any
+    pub fn xcframework_variant(&self) -> Option<&'static str> {
+        use ApplePlatform::*;
+        match self {
+            IOSSimulator | TvOSSimulator | WatchOSSimulator | VisionOSSimulator => {
+                Some("simulator")
+            }
+            MacCatalyst => Some("maccatalyst"),
+            IOS | MacOS | TvOS | WatchOS | VisionOS => None,
+        }
+    }
+
     /// Returns the platform-specific Info.plist fragments required by App Store
     /// validation. Values intentionally mirror what Xcode emits for native
     /// `.framework` bundles so third-party uploads aren't rejected.
@@ -319,71 +579,51 @@ impl ApplePlatform {
         match self {
             IOS => PlatformInfoPlist {
                 version_key: "MinimumOSVersion",
-                version_env_var: "IPHONEOS_DEPLOYMENT_TARGET",
-                default_version: "13.0",
                 supported_platform: "iPhoneOS",
                 device_family: &[1, 2],
             },
             IOSSimulator => PlatformInfoPlist {
                 version_key: "MinimumOSVersion",
-                version_env_var: "IPHONEOS_DEPLOYMENT_TARGET",
-                default_version: "13.0",
                 supported_platform: "iPhoneSimulator",
                 device_family: &[1, 2],
             },
             MacOS => PlatformInfoPlist {
                 version_key: "LSMinimumSystemVersion",
-                version_env_var: "MACOSX_DEPLOYMENT_TARGET",
-                default_version: "10.15",
                 supported_platform: "MacOSX",
                 device_family: &[],
             },
             MacCatalyst => PlatformInfoPlist {
                 version_key: "LSMinimumSystemVersion",
-                version_env_var: "MACOSX_DEPLOYMENT_TARGET",
-                default_version: "10.15",
                 supported_platform: "MacOSX",
                 device_family: &[2],
             },
             TvOS => PlatformInfoPlist {
                 version_key: "MinimumOSVersion",
-                version_env_var: "TVOS_DEPLOYMENT_TARGET",
-                default_version: "13.0",
                 supported_platform: "AppleTVOS",
                 device_family: &[3],
             },
             TvOSSimulator => PlatformInfoPlist {
                 version_key: "MinimumOSVersion",
-                version_env_var: "TVOS_DEPLOYMENT_TARGET",
-                default_version: "13.0",
                 supported_platform: "AppleTVSimulator",
                 device_family: &[3],
             },
             WatchOS => PlatformInfoPlist {
                 version_key: "MinimumOSVersion",
-                version_env_var: "WATCHOS_DEPLOYMENT_TARGET",
-                default_version: "6.0",
                 supported_platform: "WatchOS",
                 device_family: &[4],
             },
             WatchOSSimulator => PlatformInfoPlist {
                 version_key: "MinimumOSVersion",
-                version_env_var: "WATCHOS_DEPLOYMENT_TARGET",
-                default_version: "6.0",
                 supported_platform: "WatchSimulator",
                 device_family: &[4],
             },
             VisionOS => PlatformInfoPlist {
                 version_key: "MinimumOSVersion",
-                version_env_var: "XROS_DEPLOYMENT_TARGET",
-                default_version: "1.0",
                 supported_platform: "XROS",
                 device_family: &[7],
             },
             VisionOSSimulator => PlatformInfoPlist {
                 version_key: "MinimumOSVersion",
-                version_env_var: "XROS_DEPLOYMENT_TARGET",
-                default_version: "1.0",
                 supported_platform: "XRSimulator",
                 device_family: &[7],
             },
@@ -456,3 +696,53 @@ impl ApplePlatform {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARCH: &str = "aarch64-apple-ios";
+
+    fn native_libs_path(mode: &Mode, settings: TargetSettings) -> Utf8PathBuf {
+        let target_settings = HashMap::from([(ARCH, settings)]);
+        NativeStaticLibs::new(
+            Utf8Path::new("/native-static-libs"),
+            mode,
+            &CargoOptions::default(),
+            &target_settings,
+        )
+        .path(ARCH)
+    }
+
+    #[test]
+    fn names_native_static_libs_after_build_options() {
+        let default = native_libs_path(&Mode::Debug, TargetSettings::default());
+        assert!(default
+            .as_str()
+            .starts_with("/native-static-libs/aarch64-apple-ios-"));
+        assert_eq!(
+            native_libs_path(&Mode::Debug, TargetSettings::default()),
+            default
+        );
+
+        let mut features = TargetSettings::default();
+        features.features.features = Some(vec!["tls".to_owned()]);
+        let rustflags = TargetSettings {
+            rustflags: vec!["-Ctarget-cpu=apple-a14".to_owned()],
+            ..Default::default()
+        };
+        let env = TargetSettings {
+            env: [("IPHONEOS_DEPLOYMENT_TARGET".to_owned(), "15.0".to_owned())].into(),
+            ..Default::default()
+        };
+        let paths = [
+            native_libs_path(&Mode::Release, TargetSettings::default()),
+            native_libs_path(&Mode::Debug, features),
+            native_libs_path(&Mode::Debug, rustflags),
+            native_libs_path(&Mode::Debug, env),
+        ];
+        for path in &paths {
+            assert_ne!(*path, default);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use askama::Template;
+use serde::Serialize;
 
 #[derive(Template)]
 #[template(path = "template.toml", escape = "none")]
@@ -36,4 +37,23 @@ pub(crate) struct PackageSwift<'a> {
     pub(crate) disable_warnings: bool,
     pub(crate) platforms: &'a str,
     pub(crate) swift_tools_version: &'a str,
+    pub(crate) remote: Option<&'a RemoteBinaryTarget>,
+    pub(crate) has_resources: bool,
+    pub(crate) manifest: &'a ManifestCode,
+}
+
+/// Swift code for the settings of Package.swift that are declared in the package options
+#[derive(Debug, Serialize)]
+pub(crate) struct ManifestCode {
+    pub(crate) product_type: Option<String>,
+    pub(crate) dependencies: Vec<String>,
+    pub(crate) target_dependencies: Vec<String>,
+    pub(crate) linker_settings: Vec<String>,
+    pub(crate) swift_settings: Vec<String>,
+}
+
+/// Location of a zipped XCFramework for `.binaryTarget(name:url:checksum:)`
+pub(crate) struct RemoteBinaryTarget {
+    pub(crate) url: String,
+    pub(crate) checksum: String,
 }
@@ -0,0 +1,410 @@
+//! Creation of universal ("fat") Mach-O files, equivalent to `lipo -create`.
+//!
+//! A universal file starts with a big-endian fat header followed by one `fat_arch` record per
+//! architecture. Each record points to a thin Mach-O file (or a static library archive of Mach-O
+//! objects) that is stored unchanged at an offset aligned to the slice's alignment.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::Result;
+
+pub(crate) const FAT_MAGIC: u32 = 0xcafe_babe;
+const FAT_HEADER_SIZE: usize = 8;
+const FAT_ARCH_SIZE: usize = 20;
+
+pub(crate) const MH_MAGIC: u32 = 0xfeed_face;
+pub(crate) const MH_MAGIC_64: u32 = 0xfeed_facf;
+/// Mask for the capability bits in the upper byte of a cpusubtype
+const CPU_SUBTYPE_MASK: u32 = 0xff00_0000;
+const CPU_TYPE_X86: u32 = 7;
+const CPU_TYPE_ARM: u32 = 12;
+const CPU_TYPE_POWERPC: u32 = 18;
+/// Mask for the ABI bits in the upper byte of a cputype (e.g. 64-bit or ILP32 on 64-bit)
+const CPU_ARCH_MASK: u32 = 0xff00_0000;
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+const AR_HEADER_SIZE: usize = 60;
+
+/// Merges the given thin Mach-O files or static libraries into a single universal file at `output`.
+///
+/// All inputs need to be built for different architectures.
+pub fn create_universal_binary<P: AsRef<Path>>(inputs: &[P], output: &Path) -> Result<()> {
+    let slices = inputs
+        .iter()
+        .map(|input| {
+            let input = input.as_ref();
+            fs::read(input).with_context(|| format!("Failed to read {input:?}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let fat = fat_binary(&slices)?;
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {parent:?}"))?;
+    }
+    fs::write(output, fat).with_context(|| format!("Failed to write {output:?}"))?;
+
+    Ok(())
+}
+
+/// CPU type, subtype and alignment of a single architecture slice
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slice {
+    cputype: u32,
+    cpusubtype: u32,
+    align: u32,
+}
+
+/// Returns the contents of a universal file containing all given thin slices.
+fn fat_binary(slices: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let archs = slices
+        .iter()
+        .map(|slice| slice_info(slice))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    for (i, arch) in archs.iter().enumerate() {
+        if archs[..i].iter().any(|other| {
+            other.cputype == arch.cputype
+                && other.cpusubtype & !CPU_SUBTYPE_MASK == arch.cpusubtype & !CPU_SUBTYPE_MASK
+        }) {
+            return Err(anyhow!(
+                "Cannot create universal binary: multiple inputs have the same architecture (cputype {}, cpusubtype {})",
+                arch.cputype,
+                arch.cpusubtype & !CPU_SUBTYPE_MASK
+            )
+            .into());
+        }
+    }
+
+    let mut offset = FAT_HEADER_SIZE + FAT_ARCH_SIZE * slices.len();
+    let mut headers = Vec::with_capacity(offset);
+    headers.extend(FAT_MAGIC.to_be_bytes());
+    headers.extend((slices.len() as u32).to_be_bytes());
+
+    let mut offsets = Vec::with_capacity(slices.len());
+    for (slice, arch) in slices.iter().zip(&archs) {
+        offset = offset.next_multiple_of(1 << arch.align);
+        let (Ok(fat_offset), Ok(size)) = (u32::try_from(offset), u32::try_from(slice.len())) else {
+            return Err("Cannot create universal binary: inputs exceed 4 GiB".into());
+        };
+
+        headers.extend(arch.cputype.to_be_bytes());
+        headers.extend(arch.cpusubtype.to_be_bytes());
+        headers.extend(fat_offset.to_be_bytes());
+        headers.extend(size.to_be_bytes());
+        headers.extend(arch.align.to_be_bytes());
+
+        offsets.push(offset);
+        offset += slice.len();
+    }
+
+    let mut fat = headers;
+    for (slice, offset) in slices.iter().zip(offsets) {
+        fat.resize(offset, 0);
+        fat.extend_from_slice(slice);
+    }
+
+    Ok(fat)
+}
+
+fn slice_info(data: &[u8]) -> anyhow::Result<Slice> {
+    if data.starts_with(AR_MAGIC) {
+        let object = first_archive_object(data)?;
+        let (cputype, cpusubtype) = mach_header(object)?;
+        // Archives only need to be aligned for the object files they contain
+        let align = if read_u32(object, 0) == Some(MH_MAGIC_64) {
+            3
+        } else {
+            2
+        };
+
+        return Ok(Slice {
+            cputype,
+            cpusubtype,
+            align,
+        });
+    }
+
+    let (cputype, cpusubtype) = mach_header(data)?;
+    Ok(Slice {
+        cputype,
+        cpusubtype,
+        align: page_align(cputype)?,
+    })
+}
+
+/// Returns cputype and cpusubtype of a thin Mach-O file
+fn mach_header(data: &[u8]) -> anyhow::Result<(u32, u32)> {
+    match read_u32(data, 0) {
+        Some(MH_MAGIC | MH_MAGIC_64) => {}
+        Some(magic) if magic.swap_bytes() == FAT_MAGIC => {
+            bail!("Input is already a universal binary")
+        }
+        _ => bail!("Input is neither a Mach-O file nor a static library"),
+    }
+
+    let cputype = read_u32(data, 4).ok_or_else(|| anyhow!("Truncated Mach-O header"))?;
+    let cpusubtype = read_u32(data, 8).ok_or_else(|| anyhow!("Truncated Mach-O header"))?;
+
+    Ok((cputype, cpusubtype))
+}
+
+/// Returns the page size (as power of two) of the given CPU type, which Mach-O images in a
+/// universal file are aligned to, so they can be mapped directly into memory.
+fn page_align(cputype: u32) -> anyhow::Result<u32> {
+    match cputype & !CPU_ARCH_MASK {
+        CPU_TYPE_X86 | CPU_TYPE_POWERPC => Ok(12),
+        CPU_TYPE_ARM => Ok(14),
+        _ => bail!("Unsupported Mach-O cputype {cputype:#x}"),
+    }
+}
+
+/// Returns the contents of the first Mach-O object in a static library archive
+fn first_archive_object(data: &[u8]) -> anyhow::Result<&[u8]> {
+    let mut offset = AR_MAGIC.len();
+
+    while offset + AR_HEADER_SIZE <= data.len() {
+        let header = &data[offset..offset + AR_HEADER_SIZE];
+        let name = String::from_utf8_lossy(&header[..16]);
+        let size: usize = String::from_utf8_lossy(&header[48..58])
+            .trim()
+            .parse()
+            .context("Invalid member size in static library")?;
+
+        let start = offset + AR_HEADER_SIZE;
+        let end = start + size;
+        if end > data.len() {
+            bail!("Truncated static library");
+        }
+
+        // BSD archives store long member names in front of the member contents
+        let name_len = match name.trim_end().strip_prefix("#1/") {
+            Some(len) => len
+                .parse()
+                .context("Invalid member name in static library")?,
+            None => 0,
+        };
+        let member = &data[(start + name_len).min(end)..end];
+
+        if matches!(read_u32(member, 0), Some(MH_MAGIC | MH_MAGIC_64)) {
+            return Ok(member);
+        }
+
+        // Members are aligned to an even offset
+        offset = end + end % 2;
+    }
+
+    bail!("Static library does not contain any Mach-O object files")
+}
+
+/// Reads a little-endian u32, which is the byte order of all Mach-O files for Apple platforms
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPU_TYPE_X86_64: u32 = 0x0100_0007;
+    const CPU_TYPE_ARM64: u32 = 0x0100_000c;
+    const CPU_TYPE_ARM64_32: u32 = 0x0200_000c;
+    const CPU_SUBTYPE_X86_64_ALL: u32 = 3;
+    const CPU_SUBTYPE_ARM64_ALL: u32 = 0;
+    const CPU_SUBTYPE_ARM64_32_V8: u32 = 1;
+    const MH_OBJECT: u32 = 0x1;
+    const MH_DYLIB: u32 = 0x6;
+
+    /// Builds a minimal Mach-O file without load commands
+    fn macho(magic: u32, cputype: u32, cpusubtype: u32, filetype: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(magic.to_le_bytes());
+        data.extend(cputype.to_le_bytes());
+        data.extend(cpusubtype.to_le_bytes());
+        data.extend(filetype.to_le_bytes());
+        data.extend([0; 12]);
+        if magic == MH_MAGIC_64 {
+            data.extend([0; 4]);
+        }
+        data
+    }
+
+    fn ar_member(name: &str, contents: &[u8]) -> Vec<u8> {
+        let (header_name, contents) = if name.len() > 16 {
+            let mut long = name.as_bytes().to_vec();
+            long.extend(contents);
+            (format!("#1/{}", name.len()), long)
+        } else {
+            (name.to_owned(), contents.to_vec())
+        };
+
+        let mut member = format!(
+            "{header_name:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            0,
+            0,
+            0,
+            644,
+            contents.len()
+        )
+        .into_bytes();
+        member.extend(&contents);
+        if member.len() % 2 == 1 {
+            member.push(b'\n');
+        }
+        member
+    }
+
+    /// Builds a BSD static library with a symbol table and the given objects
+    fn archive(objects: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut data = AR_MAGIC.to_vec();
+        data.extend(ar_member("__.SYMDEF SORTED", &[0; 9]));
+        for (name, object) in objects {
+            data.extend(ar_member(name, object));
+        }
+        data
+    }
+
+    fn fat_arch(fat: &[u8], index: usize) -> [u32; 5] {
+        let start = FAT_HEADER_SIZE + index * FAT_ARCH_SIZE;
+        std::array::from_fn(|i| {
+            u32::from_be_bytes(fat[start + i * 4..start + i * 4 + 4].try_into().unwrap())
+        })
+    }
+
+    fn slice(fat: &[u8], index: usize) -> &[u8] {
+        let [_, _, offset, size, _] = fat_arch(fat, index);
+        &fat[offset as usize..(offset + size) as usize]
+    }
+
+    #[test]
+    fn merges_dylibs_with_page_alignment() {
+        let x86 = macho(
+            MH_MAGIC_64,
+            CPU_TYPE_X86_64,
+            CPU_SUBTYPE_X86_64_ALL,
+            MH_DYLIB,
+        );
+        let arm = macho(MH_MAGIC_64, CPU_TYPE_ARM64, CPU_SUBTYPE_ARM64_ALL, MH_DYLIB);
+
+        let fat = fat_binary(&[x86.clone(), arm.clone()]).unwrap();
+
+        assert_eq!(&fat[..4], &FAT_MAGIC.to_be_bytes());
+        assert_eq!(&fat[4..8], &2u32.to_be_bytes());
+
+        let [cputype, cpusubtype, offset, size, align] = fat_arch(&fat, 0);
+        assert_eq!(
+            (cputype, cpusubtype),
+            (CPU_TYPE_X86_64, CPU_SUBTYPE_X86_64_ALL)
+        );
+        assert_eq!((offset, size, align), (0x1000, x86.len() as u32, 12));
+        assert_eq!(slice(&fat, 0), &x86[..]);
+
+        let [cputype, cpusubtype, offset, size, align] = fat_arch(&fat, 1);
+        assert_eq!(
+            (cputype, cpusubtype),
+            (CPU_TYPE_ARM64, CPU_SUBTYPE_ARM64_ALL)
+        );
+        assert_eq!((offset, size, align), (0x4000, arm.len() as u32, 14));
+        assert_eq!(slice(&fat, 1), &arm[..]);
+        assert_eq!(fat.len(), (offset + size) as usize);
+    }
+
+    #[test]
+    fn merges_static_libraries() {
+        let x86 = archive(&[(
+            "a_long_object_file_name.o",
+            macho(
+                MH_MAGIC_64,
+                CPU_TYPE_X86_64,
+                CPU_SUBTYPE_X86_64_ALL,
+                MH_OBJECT,
+            ),
+        )]);
+        let arm = archive(&[(
+            "lib.o",
+            macho(
+                MH_MAGIC_64,
+                CPU_TYPE_ARM64,
+                CPU_SUBTYPE_ARM64_ALL,
+                MH_OBJECT,
+            ),
+        )]);
+        let arm64_32 = archive(&[(
+            "lib.o",
+            macho(
+                MH_MAGIC,
+                CPU_TYPE_ARM64_32,
+                CPU_SUBTYPE_ARM64_32_V8,
+                MH_OBJECT,
+            ),
+        )]);
+
+        let fat = fat_binary(&[x86.clone(), arm.clone(), arm64_32.clone()]).unwrap();
+
+        let [cputype, _, offset, _, align] = fat_arch(&fat, 0);
+        assert_eq!((cputype, offset, align), (CPU_TYPE_X86_64, 72, 3));
+        assert_eq!(slice(&fat, 0), &x86[..]);
+
+        let [cputype, _, offset, _, align] = fat_arch(&fat, 1);
+        assert_eq!((cputype, align), (CPU_TYPE_ARM64, 3));
+        assert_eq!(offset % 8, 0);
+        assert_eq!(slice(&fat, 1), &arm[..]);
+
+        let [cputype, _, offset, _, align] = fat_arch(&fat, 2);
+        assert_eq!((cputype, align), (CPU_TYPE_ARM64_32, 2));
+        assert_eq!(offset % 4, 0);
+        assert_eq!(slice(&fat, 2), &arm64_32[..]);
+    }
+
+    #[test]
+    fn rejects_duplicate_architectures() {
+        let arm = macho(MH_MAGIC_64, CPU_TYPE_ARM64, CPU_SUBTYPE_ARM64_ALL, MH_DYLIB);
+        let arm_lib64 = macho(
+            MH_MAGIC_64,
+            CPU_TYPE_ARM64,
+            CPU_SUBTYPE_ARM64_ALL | 0x8000_0000,
+            MH_DYLIB,
+        );
+
+        assert!(fat_binary(&[arm.clone(), arm_lib64]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_files() {
+        let arm = macho(MH_MAGIC_64, CPU_TYPE_ARM64, CPU_SUBTYPE_ARM64_ALL, MH_DYLIB);
+
+        assert!(fat_binary(&[arm.clone(), b"not a mach-o file".to_vec()]).is_err());
+        assert!(fat_binary(&[archive(&[("empty.txt", b"text".to_vec())])]).is_err());
+        assert!(fat_binary(&[fat_binary(&[arm]).unwrap()]).is_err());
+    }
+
+    #[test]
+    fn writes_universal_file() {
+        let dir =
+            std::env::temp_dir().join(format!("cargo-swift-universal-{}", std::process::id()));
+        let x86_path = dir.join("x86_64/libfoo.dylib");
+        let arm_path = dir.join("aarch64/libfoo.dylib");
+        let output = dir.join("universal/libfoo.dylib");
+        fs::create_dir_all(x86_path.parent().unwrap()).unwrap();
+        fs::create_dir_all(arm_path.parent().unwrap()).unwrap();
+
+        let x86 = macho(
+            MH_MAGIC_64,
+            CPU_TYPE_X86_64,
+            CPU_SUBTYPE_X86_64_ALL,
+            MH_DYLIB,
+        );
+        let arm = macho(MH_MAGIC_64, CPU_TYPE_ARM64, CPU_SUBTYPE_ARM64_ALL, MH_DYLIB);
+        fs::write(&x86_path, &x86).unwrap();
+        fs::write(&arm_path, &arm).unwrap();
+
+        create_universal_binary(&[&x86_path, &arm_path], &output).unwrap();
+
+        assert_eq!(fs::read(&output).unwrap(), fat_binary(&[x86, arm]).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
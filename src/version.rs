@@ -0,0 +1,86 @@
+use std::{fmt::Display, str::FromStr};
+
+/// A version like `15`, `10.15` or `13.4.1`, as used for platform minimum versions and the
+/// swift-tools-version. Components can be separated by dots or underscores, e.g. `10_15`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch: 0,
+        }
+    }
+}
+
+/// Formats the version with at least major and minor component, e.g. `15.0` or `13.4.1`
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.patch != 0 {
+            write!(f, ".{}", self.patch)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid version `{s}`, expected e.g. `15`, `10.15` or `10_15`");
+
+        let components = s
+            .split(['.', '_'])
+            .map(|c| c.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match components.as_slice() {
+            [major] => Ok(Self::new(*major, 0)),
+            [major, minor] => Ok(Self::new(*major, *minor)),
+            [major, minor, patch] => Ok(Self {
+                major: *major,
+                minor: *minor,
+                patch: *patch,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dotted_and_underscored_versions() {
+        assert_eq!("15".parse(), Ok(Version::new(15, 0)));
+        assert_eq!("10.15".parse(), Ok(Version::new(10, 15)));
+        assert_eq!("10_15".parse(), Ok(Version::new(10, 15)));
+        assert_eq!(
+            "13_4.1".parse(),
+            Ok(Version {
+                major: 13,
+                minor: 4,
+                patch: 1
+            })
+        );
+
+        for invalid in ["", "ios", "10.x", "10..15", "1.2.3.4"] {
+            assert!(invalid.parse::<Version>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn displays_patch_only_if_set() {
+        assert_eq!(Version::new(15, 0).to_string(), "15.0");
+        assert_eq!("10_15".parse::<Version>().unwrap().to_string(), "10.15");
+        assert_eq!("13.4.1".parse::<Version>().unwrap().to_string(), "13.4.1");
+    }
+}
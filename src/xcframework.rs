@@ -1,100 +1,15 @@
-use crate::console::Error;
+use crate::install_name::set_install_name;
 use crate::lib_type::LibType;
-use crate::targets::ApplePlatform;
-use crate::{Mode, Result, Target};
+use crate::package::TargetSettings;
+use crate::targets::{ApplePlatform, Artifacts};
+use crate::version::Version;
+use crate::{sorted_dir_entries, Mode, Result, Target};
 use anyhow::{anyhow, Context};
-use std::fs::{self, remove_dir_all, DirEntry};
+use camino::Utf8Path;
+use std::collections::HashMap;
+use std::fs::{self, remove_dir_all};
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-
-pub fn search_subframework_paths(output_dir: &Path) -> Result<Vec<PathBuf>> {
-    let mut xcf_path: Option<DirEntry> = None;
-    for sub_dir in std::fs::read_dir(output_dir)?.flatten() {
-        if sub_dir
-            .file_name()
-            .to_str()
-            .ok_or(anyhow!(
-                "The directory that is being checked if it is an XCFramework has an invalid name!"
-            ))?
-            .contains(".xcframework")
-        {
-            xcf_path = Some(sub_dir)
-        }
-    }
-    let mut subframework_paths = Vec::<PathBuf>::new();
-    if let Some(path) = xcf_path {
-        for subdir in std::fs::read_dir(path.path())? {
-            let subdir = subdir?;
-            let subdir_path = subdir.path();
-            if subdir.file_type()?.is_dir() {
-                subframework_paths.push(subdir_path);
-            }
-        }
-    } else {
-        return Err(Error::new(format!(
-            "failed to find .xcframework in {output_dir:?}"
-        )));
-    }
-    Ok(subframework_paths)
-}
-
-pub fn patch_subframework(
-    sf_dir: &Path,
-    generated_dir: &Path,
-    ffi_module_name: &str,
-) -> Result<()> {
-    // xcodebuild creates lowercase "headers", but we rename to uppercase "Headers" (Apple convention)
-    let mut headers = sf_dir.to_owned();
-    headers.push("headers");
-    remove_dir_all(&headers)
-        .with_context(|| format!("Failed to remove unpatched directory {headers:?}"))?;
-    let mut generated_headers = generated_dir.to_owned();
-    generated_headers.push("headers");
-
-    let mut patched_headers = sf_dir.to_owned();
-    patched_headers.push("Headers");
-    patched_headers.push(ffi_module_name);
-    std::fs::create_dir_all(&patched_headers)
-        .with_context(|| format!("Failed to create empty patched directory {patched_headers:?}"))?;
-
-    let mut gen_header_files = Vec::<PathBuf>::new();
-    for file in std::fs::read_dir(&generated_headers).with_context(|| {
-        format!("Failed to read from the generated header directory {patched_headers:?}")
-    })? {
-        let file = file?;
-        gen_header_files.push(file.path());
-    }
-
-    for path in gen_header_files {
-        let filename = path
-            .components()
-            .next_back()
-            .ok_or(anyhow!("Expected source filename when copying"))?;
-        patched_headers.push(filename);
-        std::fs::copy(&path, &patched_headers).with_context(|| {
-            format!("Failed to copy header file from {path:?} to {patched_headers:?}")
-        })?;
-        let _copied_file = patched_headers.pop();
-    }
-
-    Ok(())
-}
-
-pub fn patch_xcframework(
-    output_dir: &Path,
-    generated_dir: &Path,
-    ffi_module_name: &str,
-) -> Result<()> {
-    let subframeworks =
-        search_subframework_paths(output_dir).context("Failed to get subframework components")?;
-    for subframework in subframeworks {
-        patch_subframework(&subframework, generated_dir, ffi_module_name)
-            .with_context(|| format!("Failed to patch {subframework:?}"))?;
-    }
-
-    Ok(())
-}
 
 /// Creates a .framework bundle wrapping a dynamic library for a single platform slice.
 ///
@@ -124,13 +39,15 @@ pub fn patch_xcframework(
 ///     │   └── Resources/Info.plist
 ///     └── Current          -> A
 /// ```
+#[allow(clippy::too_many_arguments)]
 fn create_framework_bundle(
-    dylib_path: &str,
+    dylib_path: &Utf8Path,
     framework_name: &str,
     bundle_identifier: &str,
     headers_dir: &Path,
     output_dir: &Path,
     platform: ApplePlatform,
+    deployment_target: &str,
     privacy_manifest: Option<&Path>,
 ) -> Result<PathBuf> {
     let framework_dir = output_dir.join(format!("{framework_name}.framework"));
@@ -171,29 +88,15 @@ fn create_framework_bundle(
         format!("Failed to copy dylib from {dylib_path} to {binary_dst:?}")
     })?;
 
-    // Run install_name_tool to set the framework rpath
-    let install_name = Command::new("install_name_tool")
-        .arg("-id")
-        .arg(format!("@rpath/{framework_name}.framework/{framework_name}"))
-        .arg(&binary_dst)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .context("Failed to run install_name_tool")?;
-
-    if !install_name.status.success() {
-        return Err(anyhow!(
-            "install_name_tool failed: {}",
-            String::from_utf8_lossy(&install_name.stderr)
-        )
-        .into());
-    }
+    set_install_name(
+        &binary_dst,
+        &format!("@rpath/{framework_name}.framework/{framework_name}"),
+    )?;
 
     // Copy header files and modulemap from generated/headers/
-    for entry in fs::read_dir(headers_dir)
+    for entry in sorted_dir_entries(headers_dir)
         .with_context(|| format!("Failed to read headers dir {headers_dir:?}"))?
     {
-        let entry = entry?;
         let path = entry.path();
         let Some(name) = path.file_name() else {
             continue;
@@ -216,8 +119,7 @@ fn create_framework_bundle(
 
     // Write Info.plist
     let plist = platform.info_plist();
-    let min_version =
-        std::env::var(plist.version_env_var).unwrap_or_else(|_| plist.default_version.to_owned());
+    let min_version = platform.info_plist_version(deployment_target);
     let device_family_block = if plist.device_family.is_empty() {
         String::new()
     } else {
@@ -304,63 +206,106 @@ fn create_versioned_symlinks(framework_dir: &Path, framework_name: &str) -> Resu
     Ok(())
 }
 
+/// A single platform slice of an XCFramework, as listed under `AvailableLibraries`
+/// in its Info.plist.
+struct XCFrameworkLibrary {
+    identifier: String,
+    library_path: String,
+    binary_path: String,
+    headers_path: Option<&'static str>,
+    architectures: Vec<&'static str>,
+    platform: ApplePlatform,
+}
+
+/// Creates `{xcframework_name}.xcframework` in `output_dir`, equivalent to
+/// `xcodebuild -create-xcframework`:
+/// ```text
+/// {xcframework_name}.xcframework/
+/// ├── Info.plist
+/// ├── ios-arm64/
+/// │   ├── lib{name}.a
+/// │   └── Headers/{ffi_module_name}/
+/// └── ios-arm64_x86_64-simulator/
+///     └── ...
+/// ```
+/// For dynamic libraries, each slice contains a `.framework` bundle instead.
 #[allow(clippy::too_many_arguments)]
 pub fn create_xcframework(
     targets: &[Target],
-    lib_name: &str,
+    artifacts: &Artifacts,
+    target_dir: &Utf8Path,
     xcframework_name: &str,
     ffi_module_name: &str,
     generated_dir: &Path,
     output_dir: &Path,
-    mode: Mode,
+    mode: &Mode,
     lib_type: LibType,
+    target_settings: &HashMap<&str, TargetSettings>,
     privacy_manifest: Option<&Path>,
     bundle_identifier: Option<&str>,
 ) -> Result<()> {
-    let output_dir_name = &output_dir
-        .to_str()
-        .ok_or(anyhow!("Output directory has an invalid name!"))?;
-
-    let framework = format!("{output_dir_name}/{xcframework_name}.xcframework");
-
-    let mut xcodebuild = Command::new("xcodebuild");
-    xcodebuild.arg("-create-xcframework");
-
-    match lib_type {
-        LibType::Static => {
-            let libs: Vec<_> = targets
-                .iter()
-                .map(|t| t.library_path(lib_name, mode, lib_type))
-                .collect();
-
-            let headers = generated_dir.join("headers");
-            let headers = headers
-                .to_str()
-                .ok_or(anyhow!("Directory for bindings has an invalid name!"))?;
+    let framework_dir = output_dir.join(format!("{xcframework_name}.xcframework"));
+    if framework_dir.exists() {
+        remove_dir_all(&framework_dir)
+            .with_context(|| format!("Failed to remove old XCFramework {framework_dir:?}"))?;
+    }
 
-            for lib in &libs {
-                xcodebuild.arg("-library");
-                xcodebuild.arg(lib);
-                xcodebuild.arg("-headers");
-                xcodebuild.arg(headers);
-            }
+    let headers_dir = generated_dir.join("headers");
+    let default_id = format!("com.cargo-swift.{xcframework_name}");
+    let bundle_id = bundle_identifier.unwrap_or(&default_id);
+
+    let mut libraries: Vec<XCFrameworkLibrary> = Vec::new();
+    for target in targets {
+        let identifier = target.library_identifier();
+        if libraries.iter().any(|l| l.identifier == identifier) {
+            return Err(anyhow!(
+                "Multiple targets would be placed in the XCFramework as {identifier}"
+            )
+            .into());
         }
-        LibType::Dynamic => {
-            let headers_dir = generated_dir.join("headers");
-            let default_id = format!("com.cargo-swift.{xcframework_name}");
-            let bundle_id = bundle_identifier.unwrap_or(&default_id);
-
-            for target in targets {
-                let dylib_path = target.library_path(lib_name, mode, lib_type);
-                let lib_dir = PathBuf::from(target.library_directory(mode));
+        let library_dir = framework_dir.join(&identifier);
+        fs::create_dir_all(&library_dir)
+            .with_context(|| format!("Failed to create directory {library_dir:?}"))?;
+
+        let source_path = target.library_path(artifacts, target_dir, mode)?;
+        let (library_path, binary_path, headers_path) = match lib_type {
+            LibType::Static => {
+                let file_name = source_path
+                    .file_name()
+                    .ok_or_else(|| anyhow!("Invalid library path {source_path}"))?
+                    .to_owned();
+                let binary_dst = library_dir.join(&file_name);
+                fs::copy(&source_path, &binary_dst).with_context(|| {
+                    format!("Failed to copy library from {source_path} to {binary_dst:?}")
+                })?;
+                copy_headers(
+                    &headers_dir,
+                    &library_dir.join("Headers").join(ffi_module_name),
+                )?;
 
-                let fw_path = create_framework_bundle(
-                    &dylib_path,
+                (file_name.clone(), file_name, Some("Headers"))
+            }
+            LibType::Dynamic => {
+                let platform = target.platform();
+                let env_var = platform.deployment_target_env_var();
+                // Architectures of a universal library can have different deployment targets, the
+                // bundle supports the lowest one
+                let deployment_target = target
+                    .architectures()
+                    .into_iter()
+                    .filter_map(|arch| target_settings.get(arch)?.env.get(env_var))
+                    .filter_map(|version| version.parse::<Version>().ok())
+                    .min()
+                    .ok_or_else(|| anyhow!("No deployment target for {}", target.display_name()))?
+                    .to_string();
+                create_framework_bundle(
+                    &source_path,
                     xcframework_name,
                     bundle_id,
                     &headers_dir,
-                    &lib_dir,
-                    target.platform(),
+                    &library_dir,
+                    platform,
+                    &deployment_target,
                     privacy_manifest,
                 )
                 .with_context(|| {
@@ -370,28 +315,116 @@ pub fn create_xcframework(
                     )
                 })?;
 
-                xcodebuild.arg("-framework");
-                xcodebuild.arg(&fw_path);
+                let library_path = format!("{xcframework_name}.framework");
+                let binary_path = if target.platform().uses_versioned_bundle() {
+                    format!("{library_path}/Versions/A/{xcframework_name}")
+                } else {
+                    format!("{library_path}/{xcframework_name}")
+                };
+                (library_path, binary_path, None)
             }
-        }
+        };
+
+        libraries.push(XCFrameworkLibrary {
+            identifier,
+            library_path,
+            binary_path,
+            headers_path,
+            architectures: target.apple_architectures(),
+            platform: target.platform(),
+        });
     }
 
-    let output = xcodebuild
-        .arg("-output")
-        .arg(&framework)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()?;
+    // Sorted, so that the order of the platforms given by the user does not change the Info.plist
+    libraries.sort_by(|a, b| a.identifier.cmp(&b.identifier));
+    let info_plist = xcframework_info_plist(&libraries);
+    fs::write(framework_dir.join("Info.plist"), info_plist)
+        .context("Failed to write XCFramework Info.plist")?;
 
-    if !output.status.success() {
-        Err(output.stderr.into())
-    } else {
-        // Only patch headers for static libraries — for dynamic, headers are already
-        // inside each .framework bundle and xcodebuild preserves them as-is.
-        if matches!(lib_type, LibType::Static) {
-            patch_xcframework(output_dir, generated_dir, ffi_module_name)
-                .context("Failed to patch the XCFramework")?;
-        }
-        Ok(())
+    Ok(())
+}
+
+/// Copies all generated headers and the modulemap into `destination`
+fn copy_headers(headers_dir: &Path, destination: &Path) -> Result<()> {
+    fs::create_dir_all(destination)
+        .with_context(|| format!("Failed to create headers directory {destination:?}"))?;
+
+    for entry in sorted_dir_entries(headers_dir)
+        .with_context(|| format!("Failed to read headers dir {headers_dir:?}"))?
+    {
+        let path = entry.path();
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        let dst = destination.join(name);
+        fs::copy(&path, &dst)
+            .with_context(|| format!("Failed to copy header file from {path:?} to {dst:?}"))?;
     }
+
+    Ok(())
+}
+
+fn xcframework_info_plist(libraries: &[XCFrameworkLibrary]) -> String {
+    let available_libraries: String = libraries
+        .iter()
+        .map(|library| {
+            let headers_path = library
+                .headers_path
+                .map(|path| plist_entry("HeadersPath", path))
+                .unwrap_or_default();
+            let architectures: String = library
+                .architectures
+                .iter()
+                .map(|arch| format!("                <string>{arch}</string>\n"))
+                .collect();
+            let variant = library
+                .platform
+                .xcframework_variant()
+                .map(|variant| plist_entry("SupportedPlatformVariant", variant))
+                .unwrap_or_default();
+
+            format!(
+                r#"        <dict>
+            <key>BinaryPath</key>
+            <string>{binary_path}</string>
+{headers_path}            <key>LibraryIdentifier</key>
+            <string>{identifier}</string>
+            <key>LibraryPath</key>
+            <string>{library_path}</string>
+            <key>SupportedArchitectures</key>
+            <array>
+{architectures}            </array>
+            <key>SupportedPlatform</key>
+            <string>{platform}</string>
+{variant}        </dict>
+"#,
+                binary_path = library.binary_path,
+                identifier = library.identifier,
+                library_path = library.library_path,
+                platform = library.platform.xcframework_platform(),
+            )
+        })
+        .collect();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>AvailableLibraries</key>
+    <array>
+{available_libraries}    </array>
+    <key>CFBundlePackageType</key>
+    <string>XFWK</string>
+    <key>XCFrameworkFormatVersion</key>
+    <string>1.0</string>
+</dict>
+</plist>
+"#
+    )
+}
+
+/// Formats an optional string entry of an `AvailableLibraries` dictionary
+fn plist_entry(key: &str, value: &str) -> String {
+    format!("            <key>{key}</key>\n            <string>{value}</string>\n")
 }
@@ -0,0 +1,359 @@
+//! Minimal writer for zip archives of XCFrameworks, as expected by SwiftPM for remote binary
+//! targets.
+//!
+//! The archive only depends on the names, contents and permissions of the archived files: entries
+//! are written in sorted order with a fixed timestamp and stored without compression. Symlinks
+//! of versioned macOS frameworks are kept as symlinks by storing their Unix mode.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::{sorted_dir_entries, Result};
+
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+
+/// Zip 1.0, which supports stored entries and directories
+const VERSION_NEEDED: u16 = 10;
+/// Unix host, so that extractors restore the mode in the external attributes
+const VERSION_MADE_BY: u16 = (3 << 8) | 20;
+/// Names are encoded as UTF-8
+const FLAGS: u16 = 1 << 11;
+/// 1980-01-01 00:00, the earliest date representable in a zip archive
+const DOS_DATE: u16 = (1 << 5) | 1;
+const DOS_TIME: u16 = 0;
+
+const MODE_DIR: u32 = 0o040755;
+const MODE_FILE: u32 = 0o100644;
+const MODE_EXECUTABLE: u32 = 0o100755;
+const MODE_SYMLINK: u32 = 0o120755;
+/// MS-DOS directory attribute
+const ATTRIBUTE_DIR: u32 = 0x10;
+
+struct Entry {
+    name: String,
+    mode: u32,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes the directory at `dir` including the directory itself to a new zip archive at
+/// `zip_path`, e.g. `MyLib.xcframework/Info.plist` for `dir` = `MyLib.xcframework`
+pub fn zip_dir(dir: &Path, zip_path: &Path) -> Result<()> {
+    let name = dir
+        .file_name()
+        .with_context(|| format!("Invalid directory to zip: {dir:?}"))?
+        .to_string_lossy()
+        .into_owned();
+    let file = File::create(zip_path).with_context(|| format!("Failed to create {zip_path:?}"))?;
+    let mut writer = ZipWriter {
+        out: BufWriter::new(file),
+        offset: 0,
+        entries: Vec::new(),
+    };
+
+    writer.add_dir(dir, &name)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+struct ZipWriter<W: Write> {
+    out: W,
+    offset: u64,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn add_dir(&mut self, dir: &Path, name: &str) -> Result<()> {
+        self.add_entry(format!("{name}/"), MODE_DIR, &[])?;
+
+        let children =
+            sorted_dir_entries(dir).with_context(|| format!("Failed to read directory {dir:?}"))?;
+
+        for child in children {
+            let path = child.path();
+            let name = format!("{name}/{}", child.file_name().to_string_lossy());
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_symlink() {
+                let target = fs::read_link(&path)
+                    .with_context(|| format!("Failed to read symlink {path:?}"))?;
+                self.add_entry(name, MODE_SYMLINK, target.to_string_lossy().as_bytes())?;
+            } else if metadata.is_dir() {
+                self.add_dir(&path, &name)?;
+            } else {
+                let contents =
+                    fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+                let mode = if metadata.permissions().mode() & 0o111 != 0 {
+                    MODE_EXECUTABLE
+                } else {
+                    MODE_FILE
+                };
+                self.add_entry(name, mode, &contents)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_entry(&mut self, name: String, mode: u32, data: &[u8]) -> Result<()> {
+        let Ok(size) = u32::try_from(data.len()) else {
+            return Err(format!("{name} is too large for a zip archive").into());
+        };
+        let Ok(offset) = u32::try_from(self.offset) else {
+            return Err("The zip archive exceeds 4 GiB".into());
+        };
+        let entry = Entry {
+            crc: crc32(data),
+            name,
+            mode,
+            size,
+            offset,
+        };
+
+        let mut header = Vec::with_capacity(30 + entry.name.len());
+        put_u32(&mut header, LOCAL_FILE_HEADER);
+        put_u16(&mut header, VERSION_NEEDED);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, 0); // stored
+        put_u16(&mut header, DOS_TIME);
+        put_u16(&mut header, DOS_DATE);
+        put_u32(&mut header, entry.crc);
+        put_u32(&mut header, entry.size);
+        put_u32(&mut header, entry.size);
+        put_u16(&mut header, entry.name.len() as u16);
+        put_u16(&mut header, 0); // extra field length
+        header.extend_from_slice(entry.name.as_bytes());
+
+        self.write(&header)?;
+        self.write(data)?;
+        self.entries.push(entry);
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        let Ok(directory_offset) = u32::try_from(self.offset) else {
+            return Err("The zip archive exceeds 4 GiB".into());
+        };
+        let Ok(entry_count) = u16::try_from(self.entries.len()) else {
+            return Err("Too many files for a zip archive".into());
+        };
+
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            let attributes = if entry.mode == MODE_DIR {
+                (entry.mode << 16) | ATTRIBUTE_DIR
+            } else {
+                entry.mode << 16
+            };
+            put_u32(&mut directory, CENTRAL_DIRECTORY_HEADER);
+            put_u16(&mut directory, VERSION_MADE_BY);
+            put_u16(&mut directory, VERSION_NEEDED);
+            put_u16(&mut directory, FLAGS);
+            put_u16(&mut directory, 0); // stored
+            put_u16(&mut directory, DOS_TIME);
+            put_u16(&mut directory, DOS_DATE);
+            put_u32(&mut directory, entry.crc);
+            put_u32(&mut directory, entry.size);
+            put_u32(&mut directory, entry.size);
+            put_u16(&mut directory, entry.name.len() as u16);
+            put_u16(&mut directory, 0); // extra field length
+            put_u16(&mut directory, 0); // comment length
+            put_u16(&mut directory, 0); // disk number
+            put_u16(&mut directory, 0); // internal attributes
+            put_u32(&mut directory, attributes);
+            put_u32(&mut directory, entry.offset);
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+        let Ok(directory_size) = u32::try_from(directory.len()) else {
+            return Err("The zip archive exceeds 4 GiB".into());
+        };
+
+        put_u32(&mut directory, END_OF_CENTRAL_DIRECTORY);
+        put_u16(&mut directory, 0); // number of this disk
+        put_u16(&mut directory, 0); // disk with the central directory
+        put_u16(&mut directory, entry_count);
+        put_u16(&mut directory, entry_count);
+        put_u32(&mut directory, directory_size);
+        put_u32(&mut directory, directory_offset);
+        put_u16(&mut directory, 0); // comment length
+
+        self.write(&directory)?;
+        self.out.flush().context("Failed to write zip archive")?;
+
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.out
+            .write_all(data)
+            .context("Failed to write zip archive")?;
+        self.offset += data.len() as u64;
+
+        Ok(())
+    }
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 as used by zip, with the reflected polynomial 0xedb88320
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    struct ParsedEntry {
+        name: String,
+        mode: u32,
+        data: Vec<u8>,
+    }
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Reads the entries of a zip archive from its central directory and checks that they match
+    /// their local headers
+    fn parse(zip: &[u8]) -> Vec<ParsedEntry> {
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(zip, end), END_OF_CENTRAL_DIRECTORY);
+        let count = u16_at(zip, end + 10) as usize;
+        let directory_size = u32_at(zip, end + 12) as usize;
+        let mut offset = u32_at(zip, end + 16) as usize;
+        assert_eq!(offset + directory_size, end);
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            assert_eq!(u32_at(zip, offset), CENTRAL_DIRECTORY_HEADER);
+            let crc = u32_at(zip, offset + 16);
+            let size = u32_at(zip, offset + 20) as usize;
+            let name_length = u16_at(zip, offset + 28) as usize;
+            let attributes = u32_at(zip, offset + 38);
+            let local = u32_at(zip, offset + 42) as usize;
+            let name = &zip[offset + 46..offset + 46 + name_length];
+            offset += 46 + name_length;
+
+            assert_eq!(u32_at(zip, local), LOCAL_FILE_HEADER);
+            assert_eq!(u16_at(zip, local + 8), 0, "entries are stored");
+            assert_eq!(u32_at(zip, local + 14), crc);
+            assert_eq!(u32_at(zip, local + 18) as usize, size);
+            assert_eq!(u32_at(zip, local + 22) as usize, size);
+            assert_eq!(&zip[local + 30..local + 30 + name_length], name);
+            let data_offset = local + 30 + name_length;
+            let data = zip[data_offset..data_offset + size].to_vec();
+            assert_eq!(crc32(&data), crc);
+
+            entries.push(ParsedEntry {
+                name: String::from_utf8(name.to_vec()).unwrap(),
+                mode: attributes >> 16,
+                data,
+            });
+        }
+        entries
+    }
+
+    #[test]
+    fn computes_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn writes_directory_tree() {
+        let dir = std::env::temp_dir().join(format!("cargo-swift-zip-{}", std::process::id()));
+        let framework = dir.join("Foo.xcframework");
+        let versions = framework.join("macos/Foo.framework/Versions");
+        fs::create_dir_all(versions.join("A")).unwrap();
+        fs::write(framework.join("Info.plist"), "<plist/>").unwrap();
+        fs::write(versions.join("A/Foo"), [0xcf, 0xfa, 0xed, 0xfe]).unwrap();
+        fs::set_permissions(versions.join("A/Foo"), fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("A", versions.join("Current")).unwrap();
+        let zip_path = dir.join("Foo.xcframework.zip");
+
+        zip_dir(&framework, &zip_path).unwrap();
+
+        let entries = parse(&fs::read(&zip_path).unwrap());
+        let listing = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.mode))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            [
+                ("Foo.xcframework/", MODE_DIR),
+                ("Foo.xcframework/Info.plist", MODE_FILE),
+                ("Foo.xcframework/macos/", MODE_DIR),
+                ("Foo.xcframework/macos/Foo.framework/", MODE_DIR),
+                ("Foo.xcframework/macos/Foo.framework/Versions/", MODE_DIR),
+                ("Foo.xcframework/macos/Foo.framework/Versions/A/", MODE_DIR),
+                (
+                    "Foo.xcframework/macos/Foo.framework/Versions/A/Foo",
+                    MODE_EXECUTABLE
+                ),
+                (
+                    "Foo.xcframework/macos/Foo.framework/Versions/Current",
+                    MODE_SYMLINK
+                ),
+            ]
+        );
+        assert_eq!(entries[1].data, b"<plist/>");
+        assert_eq!(entries[6].data, [0xcf, 0xfa, 0xed, 0xfe]);
+        assert_eq!(entries[7].data, b"A");
+
+        // Only names, contents and permissions end up in the archive
+        let first = fs::read(&zip_path).unwrap();
+        fs::write(framework.join("Info.plist"), "<plist/>").unwrap();
+        zip_dir(&framework, &zip_path).unwrap();
+        assert_eq!(fs::read(&zip_path).unwrap(), first);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
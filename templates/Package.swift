@@ -12,19 +12,53 @@ let package = Package(
     products: [
         .library(
             name: "{{ package_name }}",
+            {%- if let Some(product_type) = manifest.product_type %}
+            type: {{ product_type }},
+            {%- endif %}
             targets: ["{{ package_name }}"]
         )
     ],
+    {% if manifest.dependencies.is_empty() -%}
     dependencies: [ ],
+    {%- else -%}
+    dependencies: [
+        {%- for dependency in manifest.dependencies %}
+        {{ dependency }},
+        {%- endfor %}
+    ],
+    {%- endif %}
     targets: [
+        {% if let Some(remote) = remote -%}
+        .binaryTarget(name: "{{ xcframework_name }}", url: "{{ remote.url }}", checksum: "{{ remote.checksum }}"),
+        {%- else -%}
         .binaryTarget(name: "{{ xcframework_name }}", path: "./{{ xcframework_name }}.xcframework"),
+        {%- endif %}
         .target(
             name: "{{ package_name }}",
             dependencies: [
                 .target(name: "{{ xcframework_name }}")
-            ]{% if disable_warnings %},
+                {%- for dependency in manifest.target_dependencies -%}
+                ,
+                {{ dependency }}
+                {%- endfor %}
+            ]{% if has_resources %},
+            resources: [
+                .process("Resources"),
+            ]
+            {%- endif %}{% if disable_warnings || !manifest.swift_settings.is_empty() %},
             swiftSettings: [
+                {%- for setting in manifest.swift_settings %}
+                {{ setting }},
+                {%- endfor %}
+                {%- if disable_warnings %}
                 .unsafeFlags(["-suppress-warnings"]),
+                {%- endif %}
+            ]
+            {%- endif %}{% if !manifest.linker_settings.is_empty() %},
+            linkerSettings: [
+                {%- for setting in manifest.linker_settings %}
+                {{ setting }},
+                {%- endfor %}
             ]
             {%- endif %}
         ),